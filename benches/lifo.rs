@@ -0,0 +1,63 @@
+//! lifo times a task spawning a child and joining it right away, next to tasks which keep
+//! yielding. Through a FIFO queue, the child would start and its woken parent would go on only
+//! after every other task had a turn, i.e. two turns of the others per round trip. Through the
+//! LIFO slot, they go first up to MAX_LIFO_RUNS times in a row before the others get a turn, so
+//! the others get fewer, which the benchmark counts as well.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use green_threads::{spawn, spawn_local, yield_thread, Runtime};
+
+const ROUNDS: u32 = 10_000;
+
+// the time per spawn and join next to other tasks yielding all the time, and how many turns
+// each of them got per round trip.
+fn round_trip(others: usize) -> (Duration, f64) {
+    let mut runtime = Runtime::builder().max_threads(others + 2).build();
+    runtime.init();
+    let done = Rc::new(Cell::new(false));
+    let turns = Rc::new(Cell::new(0_u64));
+    for _ in 0..others {
+        let (done, turns) = (done.clone(), turns.clone());
+        spawn_local(move || {
+            while !done.get() {
+                turns.set(turns.get() + 1);
+                yield_thread();
+            }
+        })
+        .unwrap();
+    }
+    let measured = Rc::new(Cell::new((Duration::default(), 0)));
+    {
+        let (turns, measured) = (turns.clone(), measured.clone());
+        spawn_local(move || {
+            let (start, from) = (Instant::now(), turns.get());
+            for i in 0..ROUNDS {
+                assert_eq!(spawn(move || i).join().unwrap(), i);
+            }
+            measured.set((start.elapsed(), turns.get() - from));
+            done.set(true);
+        })
+        .unwrap();
+    }
+    runtime.run();
+    let (elapsed, turns) = measured.get();
+    let per_task = if others == 0 {
+        0.0
+    } else {
+        turns as f64 / others as f64 / ROUNDS as f64
+    };
+    (elapsed / ROUNDS, per_task)
+}
+
+fn main() {
+    for &others in &[0, 8, 64] {
+        let (per_round_trip, turns) = round_trip(others);
+        println!(
+            "{:>2} other tasks: {:?} per spawn and join, {:.2} turns per other task",
+            others, per_round_trip, turns
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! spawn_many times spawning 100k tiny tasks with one call of spawn_many against a loop of
+//! spawn, keeping the JoinHandles either way, and running them.
+
+use std::time::{Duration, Instant};
+
+use green_threads::{JoinHandle, Runtime};
+
+const TASKS: u32 = 100_000;
+
+// the time spawning took, and the time running the tasks took.
+fn time(spawn: impl FnOnce(&mut Runtime) -> Vec<JoinHandle<u32>>) -> (Duration, Duration) {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let start = Instant::now();
+    let handles = spawn(&mut runtime);
+    let spawned = start.elapsed();
+    runtime.run();
+    let ran = start.elapsed() - spawned;
+    assert_eq!(handles.len(), TASKS as usize);
+    (spawned, ran)
+}
+
+fn main() {
+    let (spawned, ran) = time(|runtime| (0..TASKS).map(|i| runtime.spawn(move || i)).collect());
+    println!("spawn loop: spawned in {:?}, ran in {:?}", spawned, ran);
+    let (spawned, ran) = time(|runtime| runtime.spawn_many((0..TASKS).map(|i| move || i)));
+    println!("spawn_many: spawned in {:?}, ran in {:?}", spawned, ran);
+}
@@ -0,0 +1,37 @@
+//! spawn_with_arg times spawning tiny tasks with spawn_with_arg, which writes the argument to
+//! the stack of the task, against spawning closures capturing it, which boxes them.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use green_threads::Runtime;
+
+const BATCH: usize = 64;
+const BATCHES: u32 = 2000;
+
+fn consume(n: u64) {
+    black_box(n);
+}
+
+// spawn BATCH tasks at a time, since spawn_with_arg needs a free thread for each, and run them.
+fn time_batches(mut spawn: impl FnMut(&mut Runtime, u64)) -> Duration {
+    let mut runtime = Runtime::builder().max_threads(BATCH).build();
+    runtime.init();
+    let start = Instant::now();
+    for _ in 0..BATCHES {
+        for n in 0..BATCH as u64 {
+            spawn(&mut runtime, n);
+        }
+        runtime.run();
+    }
+    start.elapsed() / (BATCHES * BATCH as u32)
+}
+
+fn main() {
+    let closure = time_batches(|runtime, n| {
+        runtime.spawn(move || consume(n));
+    });
+    let with_arg = time_batches(|runtime, n| runtime.spawn_with_arg(consume, n).unwrap());
+    println!("spawn:          {:?} per task", closure);
+    println!("spawn_with_arg: {:?} per task", with_arg);
+}
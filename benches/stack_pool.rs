@@ -0,0 +1,54 @@
+//! stack_pool times 100k trivial tasks alternating between two stack sizes on one runtime,
+//! whose threads keep their stacks and swap the other size in and out of the pool, against
+//! allocating a stack for every task, which a fresh runtime per task has to.
+
+use std::time::{Duration, Instant};
+
+use green_threads::Runtime;
+
+const TASKS: u32 = 100_000;
+const BATCH: u32 = 10;
+const SIZES: [usize; 2] = [64 * 1024, 256 * 1024];
+
+// the time per task and the number of stacks allocated.
+fn pooled() -> (Duration, u64) {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let start = Instant::now();
+    for batch in 0..TASKS / BATCH {
+        for i in 0..BATCH {
+            let size = SIZES[((batch + i) % 2) as usize];
+            runtime.spawn_with_stack(size, || ()).unwrap();
+        }
+        runtime.run();
+    }
+    (start.elapsed() / TASKS, runtime.stats().stacks)
+}
+
+fn fresh() -> (Duration, u64) {
+    let mut stacks = 0;
+    let start = Instant::now();
+    for i in 0..TASKS {
+        let mut runtime = Runtime::new();
+        runtime.init();
+        runtime
+            .spawn_with_stack(SIZES[(i % 2) as usize], || ())
+            .unwrap();
+        runtime.run();
+        stacks += runtime.stats().stacks;
+    }
+    (start.elapsed() / TASKS, stacks)
+}
+
+fn main() {
+    let (per_task, stacks) = pooled();
+    println!(
+        "pooled: {:?} per task, {} stacks allocated",
+        per_task, stacks
+    );
+    let (per_task, stacks) = fresh();
+    println!(
+        "fresh:  {:?} per task, {} stacks allocated",
+        per_task, stacks
+    );
+}
@@ -0,0 +1,33 @@
+//! stack_size times spawning a task on a stack allocated for it, for several stack sizes. A
+//! stack which had to be zeroed upfront would cost in proportion to its size, while a reserved
+//! one only costs the pages the task touches, so every size should take about as long.
+
+use std::time::{Duration, Instant};
+
+use green_threads::Runtime;
+
+const ROUNDS: u32 = 2000;
+
+// spawn and run a trivial task on a fresh runtime, so it can't reuse the stack of another one.
+fn spawn_on_fresh_stack(stack_size: usize) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut runtime = Runtime::builder().default_stack_size(stack_size).build();
+        runtime.init();
+        runtime.spawn(|| ());
+        runtime.run();
+        assert_eq!(runtime.stats().stacks, 1);
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn main() {
+    for &stack_size in &[64 * 1024, 2 * 1024 * 1024, 8 * 1024 * 1024] {
+        let per_spawn = spawn_on_fresh_stack(stack_size);
+        println!(
+            "{:>5} KiB stacks: {:?} per spawn",
+            stack_size / 1024,
+            per_spawn
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! block_on runs a root thread summing the squares of some numbers with a child thread per
+//! number, and gets the sum back without spawning and running separately.
+
+use green_threads::{spawn, yield_thread, Runtime};
+
+fn main() {
+    let mut runtime = Runtime::new();
+    let numbers: Vec<u64> = (1..=10).collect();
+    // the root thread borrows numbers, since block_on waits for it to finish.
+    let sum = runtime.block_on(|| {
+        let children: Vec<_> = numbers
+            .iter()
+            .map(|&n| {
+                spawn(move || {
+                    yield_thread();
+                    n * n
+                })
+            })
+            .collect();
+        children
+            .into_iter()
+            .map(|child| child.join().unwrap())
+            .sum::<u64>()
+    });
+    assert_eq!(sum, 385);
+    println!("SUM OF SQUARES {}", sum);
+}
@@ -0,0 +1,28 @@
+//! counter runs two threads counting up to different numbers, yielding to each other after
+//! every step.
+
+use green_threads::{yield_thread, Runtime};
+
+fn main() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let id = 1;
+    runtime.spawn(move || {
+        println!("THREAD 1 STARTING");
+        for i in 0..10 {
+            println!("thread: {} counter: {}", id, i);
+            yield_thread();
+        }
+        println!("THREAD 1 FINISHED");
+    });
+    let id = 2;
+    runtime.spawn(move || {
+        println!("THREAD 2 STARTING");
+        for i in 0..15 {
+            println!("thread: {} counter: {}", id, i);
+            yield_thread();
+        }
+        println!("THREAD 2 FINISHED");
+    });
+    runtime.run();
+}
@@ -0,0 +1,41 @@
+//! fan_out spawns a root thread which spawns children, each spawning grandchildren of its own,
+//! many more than the runtime has threads for.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use green_threads::{spawn, yield_thread, Runtime};
+
+const CHILDREN: usize = 100;
+const GRANDCHILDREN: usize = 10;
+
+fn main() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let finished = Arc::new(AtomicUsize::new(0));
+    let counter = finished.clone();
+    runtime.spawn(move || {
+        let children: Vec<_> = (0..CHILDREN)
+            .map(|_| {
+                let counter = counter.clone();
+                spawn(move || {
+                    for _ in 0..GRANDCHILDREN {
+                        let counter = counter.clone();
+                        spawn(move || {
+                            yield_thread();
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        });
+                    }
+                    counter.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+    });
+    runtime.run();
+    let finished = finished.load(Ordering::Relaxed);
+    assert_eq!(finished, CHILDREN * (GRANDCHILDREN + 1));
+    println!("{} THREADS FINISHED", finished);
+}
@@ -0,0 +1,26 @@
+//! ping_pong runs two threads bouncing a counter back and forth over a pair of channels.
+
+use green_threads::{channel, Runtime};
+
+fn main() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let (ping_tx, ping_rx) = channel();
+    let (pong_tx, pong_rx) = channel();
+    runtime.spawn(move || {
+        for i in 0..5 {
+            println!("ping {}", i);
+            ping_tx.send(i).unwrap();
+            pong_rx.recv().unwrap();
+        }
+        // dropping ping_tx disconnects the channel, so the other thread stops.
+    });
+    runtime.spawn(move || {
+        while let Ok(i) = ping_rx.recv() {
+            println!("pong {}", i);
+            pong_tx.send(i).unwrap();
+        }
+        println!("PING PONG FINISHED");
+    });
+    runtime.run();
+}
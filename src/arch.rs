@@ -0,0 +1,314 @@
+//! arch holds what differs between architectures: the registers a thread is resumed with, how
+//! the stack of a new thread is primed, and the assembly switching between threads.
+
+use std::arch::naked_asm;
+#[cfg(target_arch = "x86_64")]
+use std::ptr;
+
+use crate::runtime::guard;
+
+/// ThreadContext contains the registers marked as "callee-saved" (preserved across calls)
+/// in the specification of x86-64 architecture. They contain all the information
+/// we need to resume a thread.
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+#[derive(Debug, Default)]
+#[repr(C)]
+pub(crate) struct ThreadContext {
+    rsp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+}
+
+/// ThreadContext on Windows x64 additionally holds rdi, rsi and xmm6 to xmm15, which are
+/// callee-saved there, and the bounds of the stack recorded in the Thread Information Block,
+/// which Windows checks the stack pointer against.
+#[cfg(all(target_arch = "x86_64", windows))]
+#[derive(Debug, Default)]
+#[repr(C)]
+pub(crate) struct ThreadContext {
+    xmm6: [u64; 2],
+    xmm7: [u64; 2],
+    xmm8: [u64; 2],
+    xmm9: [u64; 2],
+    xmm10: [u64; 2],
+    xmm11: [u64; 2],
+    xmm12: [u64; 2],
+    xmm13: [u64; 2],
+    xmm14: [u64; 2],
+    xmm15: [u64; 2],
+    rsp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    stack_start: u64,
+    stack_end: u64,
+}
+
+/// ThreadContext on aarch64 holds the callee-saved registers of AAPCS64: x19 to x28, the frame
+/// pointer x29, the link register x30 which ret jumps to, and the lower halves of v8 to v15.
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Default)]
+#[repr(C)]
+pub(crate) struct ThreadContext {
+    sp: u64,
+    x19: u64,
+    x20: u64,
+    x21: u64,
+    x22: u64,
+    x23: u64,
+    x24: u64,
+    x25: u64,
+    x26: u64,
+    x27: u64,
+    x28: u64,
+    x29: u64,
+    x30: u64,
+    d8: u64,
+    d9: u64,
+    d10: u64,
+    d11: u64,
+    d12: u64,
+    d13: u64,
+    d14: u64,
+    d15: u64,
+}
+
+impl ThreadContext {
+    /// prime stack so that switching to this thread calls entry and then guard. top is the
+    /// offset on the stack below which the return address of switch is written. It's
+    /// start_thread, which calls entry from r12 and then guard from r13, and ends the chain of
+    /// frames so that backtraces taken in the thread stop there.
+    #[cfg(all(target_arch = "x86_64", not(windows)))]
+    pub(crate) fn prime(&mut self, stack: &mut [u8], entry: *const (), top: usize) {
+        let top = aligned_top(stack, top);
+
+        unsafe {
+            // after start_thread is popped, rsp is 16 bytes aligned like before a call.
+            ptr::write((top - 24) as *mut u64, start_thread as *const () as u64);
+        }
+        self.rsp = (top - 24) as u64;
+        // switch's ret pops start_thread, and its call pushes a return address for entry, which
+        // then starts with rsp % 16 == 8 like any function called with an aligned stack.
+        debug_assert_eq!(self.rsp % 16, 8);
+        self.r12 = entry as u64;
+        self.r13 = guard as *const () as u64;
+        // a null frame pointer is where frame pointer walks stop.
+        self.rbp = 0;
+    }
+
+    /// prime stack so that switching to this thread calls entry and then guard. The callee
+    /// owns 32 bytes of shadow space above its return address on Windows, which would overlap
+    /// the skip and guard slots, so switch's ret goes to start_thread instead, which reserves the
+    /// shadow space and calls entry from r12 and then guard from r13.
+    #[cfg(all(target_arch = "x86_64", windows))]
+    pub(crate) fn prime(&mut self, stack: &mut [u8], entry: *const (), top: usize) {
+        let top = aligned_top(stack, top);
+
+        unsafe {
+            // after start_thread is popped, rsp is 16 bytes aligned like before a call.
+            ptr::write((top - 24) as *mut u64, start_thread as *const () as u64);
+        }
+        self.rsp = (top - 24) as u64;
+        // switch's ret pops start_thread, and its call pushes a return address for entry, which
+        // then starts with rsp % 16 == 8 like any function called with an aligned stack.
+        debug_assert_eq!(self.rsp % 16, 8);
+        self.stack_start = stack.as_ptr_range().end as u64;
+        self.stack_end = stack.as_ptr() as u64;
+        self.r12 = entry as u64;
+        self.r13 = guard as *const () as u64;
+        self.rbp = 0;
+    }
+
+    /// prime the context so that switching to this thread calls entry and then guard. There is
+    /// no return address on stack on aarch64: switch's ret jumps to x30, so it points to
+    /// start_thread, which calls entry from x19 and then guard from x20.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn prime(&mut self, stack: &mut [u8], entry: *const (), top: usize) {
+        self.sp = aligned_top(stack, top) as u64;
+        debug_assert_eq!(self.sp % 16, 0);
+        self.x19 = entry as u64;
+        self.x20 = guard as *const () as u64;
+        self.x29 = 0;
+        self.x30 = start_thread as *const () as u64;
+    }
+}
+
+// aligned_top returns the address of the offset top on stack, aligned down to the 16 bytes the
+// ABIs want the stack pointer aligned to at a call, whatever the alignment of the stack's memory
+// and the size asked for.
+fn aligned_top(stack: &mut [u8], top: usize) -> usize {
+    (stack[..top].as_mut_ptr_range().end as usize) & !15
+}
+
+/// switch saves the callee-saved registers of the running thread into old and restores the ones
+/// of the thread to resume from new, and its ret pops the resumed thread's return address.
+///
+/// It's a naked function so the compiler doesn't emit a prologue that would move rsp before we
+/// save it. Naked functions can't take asm operands, so there are no in/out/lateout operands:
+/// old and new arrive in rdi and rsi following the System V calling convention of extern "C",
+/// and every register we touch is restored from new before ret, so none is clobbered.
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "mov [rdi + 0x00], rsp",
+        "mov [rdi + 0x08], r15",
+        "mov [rdi + 0x10], r14",
+        "mov [rdi + 0x18], r13",
+        "mov [rdi + 0x20], r12",
+        "mov [rdi + 0x28], rbx",
+        "mov [rdi + 0x30], rbp",
+        "mov rsp, [rsi + 0x00]",
+        "mov r15, [rsi + 0x08]",
+        "mov r14, [rsi + 0x10]",
+        "mov r13, [rsi + 0x18]",
+        "mov r12, [rsi + 0x20]",
+        "mov rbx, [rsi + 0x28]",
+        "mov rbp, [rsi + 0x30]",
+        "ret",
+    );
+}
+
+/// start_thread is where a primed thread is switched to for the first time. It calls the entry
+/// in r12 and then the guard in r13, which never returns since a finished thread is never
+/// resumed. Its return address is marked undefined in the unwind info, which tells unwinders
+/// it's the outermost frame instead of letting them walk into whatever lies above on the stack.
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+#[unsafe(naked)]
+unsafe extern "C" fn start_thread() {
+    naked_asm!(
+        ".cfi_startproc",
+        ".cfi_undefined rip",
+        "call r12",
+        "call r13",
+        "ud2",
+        ".cfi_endproc",
+    );
+}
+
+/// switch on Windows x64 works the same, with old and new in rcx and rdx following the Microsoft
+/// x64 calling convention, and it also swaps the stack bounds in the Thread Information Block
+/// at gs:[0x08] (the base, i.e. the high end) and gs:[0x10] (the limit). The xmm registers are
+/// moved with movups since ThreadContext is only 8 bytes aligned.
+#[cfg(all(target_arch = "x86_64", windows))]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "movups [rcx + 0x00], xmm6",
+        "movups [rcx + 0x10], xmm7",
+        "movups [rcx + 0x20], xmm8",
+        "movups [rcx + 0x30], xmm9",
+        "movups [rcx + 0x40], xmm10",
+        "movups [rcx + 0x50], xmm11",
+        "movups [rcx + 0x60], xmm12",
+        "movups [rcx + 0x70], xmm13",
+        "movups [rcx + 0x80], xmm14",
+        "movups [rcx + 0x90], xmm15",
+        "mov [rcx + 0xa0], rsp",
+        "mov [rcx + 0xa8], r15",
+        "mov [rcx + 0xb0], r14",
+        "mov [rcx + 0xb8], r13",
+        "mov [rcx + 0xc0], r12",
+        "mov [rcx + 0xc8], rbx",
+        "mov [rcx + 0xd0], rbp",
+        "mov [rcx + 0xd8], rdi",
+        "mov [rcx + 0xe0], rsi",
+        "mov rax, gs:[0x08]",
+        "mov [rcx + 0xe8], rax",
+        "mov rax, gs:[0x10]",
+        "mov [rcx + 0xf0], rax",
+        "movups xmm6, [rdx + 0x00]",
+        "movups xmm7, [rdx + 0x10]",
+        "movups xmm8, [rdx + 0x20]",
+        "movups xmm9, [rdx + 0x30]",
+        "movups xmm10, [rdx + 0x40]",
+        "movups xmm11, [rdx + 0x50]",
+        "movups xmm12, [rdx + 0x60]",
+        "movups xmm13, [rdx + 0x70]",
+        "movups xmm14, [rdx + 0x80]",
+        "movups xmm15, [rdx + 0x90]",
+        "mov rsp, [rdx + 0xa0]",
+        "mov r15, [rdx + 0xa8]",
+        "mov r14, [rdx + 0xb0]",
+        "mov r13, [rdx + 0xb8]",
+        "mov r12, [rdx + 0xc0]",
+        "mov rbx, [rdx + 0xc8]",
+        "mov rbp, [rdx + 0xd0]",
+        "mov rdi, [rdx + 0xd8]",
+        "mov rsi, [rdx + 0xe0]",
+        "mov rax, [rdx + 0xe8]",
+        "mov gs:[0x08], rax",
+        "mov rax, [rdx + 0xf0]",
+        "mov gs:[0x10], rax",
+        "ret",
+    );
+}
+
+/// start_thread is where a primed thread on Windows x64 is switched to for the first time. It
+/// reserves the shadow space and calls the entry in r12 and then the guard in r13, which never
+/// returns since a finished thread is never resumed.
+#[cfg(all(target_arch = "x86_64", windows))]
+#[unsafe(naked)]
+unsafe extern "C" fn start_thread() {
+    naked_asm!("sub rsp, 0x20", "call r12", "call r13", "ud2");
+}
+
+/// switch on aarch64 works the same, with old and new in x0 and x1. sp can't be stored directly,
+/// so it goes through the caller-saved x9, and ret jumps to the restored x30.
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "mov x9, sp",
+        "str x9, [x0, 0x00]",
+        "stp x19, x20, [x0, 0x08]",
+        "stp x21, x22, [x0, 0x18]",
+        "stp x23, x24, [x0, 0x28]",
+        "stp x25, x26, [x0, 0x38]",
+        "stp x27, x28, [x0, 0x48]",
+        "stp x29, x30, [x0, 0x58]",
+        "stp d8, d9, [x0, 0x68]",
+        "stp d10, d11, [x0, 0x78]",
+        "stp d12, d13, [x0, 0x88]",
+        "stp d14, d15, [x0, 0x98]",
+        "ldr x9, [x1, 0x00]",
+        "mov sp, x9",
+        "ldp x19, x20, [x1, 0x08]",
+        "ldp x21, x22, [x1, 0x18]",
+        "ldp x23, x24, [x1, 0x28]",
+        "ldp x25, x26, [x1, 0x38]",
+        "ldp x27, x28, [x1, 0x48]",
+        "ldp x29, x30, [x1, 0x58]",
+        "ldp d8, d9, [x1, 0x68]",
+        "ldp d10, d11, [x1, 0x78]",
+        "ldp d12, d13, [x1, 0x88]",
+        "ldp d14, d15, [x1, 0x98]",
+        "ret",
+    );
+}
+
+/// start_thread is where a primed thread on aarch64 is switched to for the first time. It calls
+/// the entry in x19 and then the guard in x20, which never returns, like on x86_64, and its
+/// return address in x30 is marked undefined in the unwind info the same way, so unwinders stop
+/// at it.
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn start_thread() {
+    naked_asm!(
+        ".cfi_startproc",
+        ".cfi_undefined x30",
+        "blr x19",
+        "blr x20",
+        "brk #1",
+        ".cfi_endproc",
+    );
+}
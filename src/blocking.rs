@@ -0,0 +1,105 @@
+//! blocking holds the pool of OS threads running the closures of spawn_blocking, so that calls
+//! which block don't stall the OS thread running the runtime.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Job is a closure waiting for a thread of the pool to run it.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// BlockingPool starts its OS threads lazily, up to max_threads, and an idle thread exits once
+/// it's been waiting for a job for keep_alive. Dropping the pool lets its threads exit after the
+/// jobs already queued, without waiting for them.
+pub(crate) struct BlockingPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    // notified when a job is queued or the pool is dropped
+    condvar: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    // the number of threads alive, and how many of them are waiting for a job
+    threads: usize,
+    idle: usize,
+    shutdown: bool,
+}
+
+impl BlockingPool {
+    pub(crate) fn new(max_threads: usize, keep_alive: Duration) -> Self {
+        BlockingPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    shutdown: false,
+                }),
+                condvar: Condvar::new(),
+                max_threads,
+                keep_alive,
+            }),
+        }
+    }
+
+    // queue a job and wake up an idle thread for it. If there are more queued jobs than idle
+    // threads, a new thread is started unless the pool is full, in which case the job waits for
+    // a busy thread to get to it.
+    pub(crate) fn execute(&self, job: Job) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(job);
+        if state.queue.len() > state.idle && state.threads < self.shared.max_threads {
+            state.threads += 1;
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name("green-threads-blocking".to_string())
+                .spawn(move || work(shared))
+                .expect("failed to spawn a blocking thread");
+        }
+        self.shared.condvar.notify_one();
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.condvar.notify_all();
+    }
+}
+
+// work runs the queued jobs until the pool is dropped, or nothing has been queued for keep_alive.
+fn work(shared: Arc<Shared>) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            drop(state);
+            // the job catches panics to hand them to its JoinHandle, so this is only a backstop
+            // keeping the thread alive.
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            state = shared.state.lock().unwrap();
+            continue;
+        }
+        if state.shutdown {
+            break;
+        }
+        state.idle += 1;
+        let (guard, timeout) = shared
+            .condvar
+            .wait_timeout(state, shared.keep_alive)
+            .unwrap();
+        state = guard;
+        state.idle -= 1;
+        if timeout.timed_out() && state.queue.is_empty() {
+            break;
+        }
+    }
+    state.threads -= 1;
+}
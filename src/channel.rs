@@ -0,0 +1,148 @@
+//! channel holds a cooperative channel for tasks to send values to each other without blocking
+//! the OS thread running them.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::runtime::consume_budget;
+use crate::task::wait;
+
+/// channel creates an unbounded channel with many senders and a single receiver. Sending never
+/// waits, and receiving yields to other threads until a value is sent.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        senders: 1,
+        receiver: true,
+        waiter: None,
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Sender is the sending half of a channel. It can be cloned to send from several threads, and
+/// the channel is disconnected once all the senders are dropped.
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// Receiver is the receiving half of a channel.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+// Shared is the state of a channel shared by its senders and its receiver.
+struct Shared<T> {
+    queue: VecDeque<T>,
+    // the number of senders alive
+    senders: usize,
+    // whether the receiver is alive
+    receiver: bool,
+    // an OS thread parked in recv, waiting to be unparked by a sender
+    waiter: Option<thread::Thread>,
+}
+
+/// Disconnected is returned by Receiver::recv when the channel is empty and all its senders
+/// have been dropped, so nothing will ever be received.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel is empty and disconnected")
+    }
+}
+
+impl error::Error for Disconnected {}
+
+/// SendError is returned by Sender::send when the receiver has been dropped, with the value
+/// which couldn't be sent.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a channel whose receiver is dropped")
+    }
+}
+
+impl<T> error::Error for SendError<T> {}
+
+impl<T> Sender<T> {
+    /// send a value to the receiver. It returns immediately, and fails with the value if the
+    /// receiver has been dropped.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.receiver {
+            return Err(SendError(value));
+        }
+        shared.queue.push_back(value);
+        if let Some(waiter) = shared.waiter.take() {
+            waiter.unpark();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        // wake up the receiver to see the channel is disconnected.
+        if shared.senders == 0 {
+            if let Some(waiter) = shared.waiter.take() {
+                waiter.unpark();
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for a value and return it. On the thread running the runtime it yields to other
+    /// threads while waiting, otherwise it parks the calling OS thread until a value is sent.
+    /// If all the other threads of the runtime are waiting as well, it parks until a value is
+    /// sent from another OS thread. It fails with Disconnected once the channel is empty and all
+    /// the senders are dropped.
+    pub fn recv(&self) -> Result<T, Disconnected> {
+        consume_budget();
+        wait(|| {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(value) = shared.queue.pop_front() {
+                return Some(Ok(value));
+            }
+            if shared.senders == 0 {
+                return Some(Err(Disconnected));
+            }
+            shared.waiter = Some(thread::current());
+            None
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receiver = false;
+    }
+}
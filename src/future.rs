@@ -0,0 +1,84 @@
+//! future holds the bridge running futures on threads, so that async code can be reused without
+//! an async runtime: every .await which isn't ready yet is a yield to other threads.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use crate::runtime::spawn;
+use crate::task::{wait, JoinHandle};
+
+/// spawn_future spawns a thread polling fut until it's ready, on the runtime initialized on the
+/// calling OS thread, like the spawn function. Whenever fut returns Poll::Pending, the thread
+/// yields to other threads and polls it again when it's resumed, so waking isn't needed, but
+/// if every thread is waiting, the runtime parks until fut is woken from another OS thread. It
+/// panics if no runtime is initialized on the calling OS thread.
+///
+/// ```
+/// use std::future::Future;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+///
+/// use green_threads::{spawn_future, Runtime};
+///
+/// // ready after being polled n times.
+/// struct Countdown(u32);
+///
+/// impl Future for Countdown {
+///     type Output = &'static str;
+///
+///     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+///         if self.0 == 0 {
+///             return Poll::Ready("liftoff");
+///         }
+///         self.0 -= 1;
+///         cx.waker().wake_by_ref();
+///         Poll::Pending
+///     }
+/// }
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let handle = spawn_future(async {
+///     let first = Countdown(3).await;
+///     let second = Countdown(5).await;
+///     (first, second)
+/// });
+/// runtime.run();
+/// assert_eq!(handle.join().unwrap(), ("liftoff", "liftoff"));
+/// ```
+pub fn spawn_future<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn(move || run(fut))
+}
+
+// run polls fut until it's ready, yielding to other threads in between, the same way
+// JoinHandle::join waits.
+pub(crate) fn run<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    wait(|| match fut.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    })
+}
+
+// Unpark wakes a future up by unparking the OS thread polling it, in case it's parked waiting
+// for another OS thread. A future polled by a thread which is yielding is polled again anyway.
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
@@ -0,0 +1,29 @@
+//! green-threads is a toy implementation on user-space threads in non-preemptive multitasking.
+//! This implementation is mostly guided by cfsamson's tutorial:
+//! https://cfsamson.gitbook.io/green-threads-explained-in-200-lines-of-rust/green-threads.
+#![deny(missing_docs)]
+
+mod arch;
+mod blocking;
+mod channel;
+mod future;
+mod local;
+#[cfg(target_os = "linux")]
+pub mod net;
+mod preempt;
+mod runtime;
+mod stack;
+mod sync;
+mod task;
+
+pub use channel::{channel, Disconnected, Receiver, SendError, Sender};
+pub use future::spawn_future;
+pub use local::TaskLocal;
+pub use runtime::{
+    consume_budget, current, current_name, ensure_sufficient_stack, in_runtime, is_shutting_down,
+    park, preemption_point, remaining_stack, shutdown, sleep, spawn, spawn_blocking, spawn_local,
+    try_spawn, try_yield, unpark, yield_thread, yield_thread_front, yield_to, Error, Handle,
+    Priority, Runtime, RuntimeBuilder, RuntimeStats, Scope, TaskBuilder,
+};
+pub use sync::{Condvar, Mutex, MutexGuard, Semaphore, SemaphorePermit};
+pub use task::{join_all, wait_any, JoinError, JoinHandle, TaskId};
@@ -0,0 +1,67 @@
+//! local holds the storage of values local to each task, like thread_local does for OS threads.
+
+use std::any::Any;
+
+use crate::runtime::{with_locals, Error};
+
+/// TaskLocal is a key to a value every task has its own instance of. The instance is created
+/// with init the first time a task accesses it, and dropped when the task finishes. It's
+/// usually declared with the task_local macro.
+pub struct TaskLocal<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: 'static> TaskLocal<T> {
+    /// create a key whose instances start as the value returned by init.
+    pub const fn new(init: fn() -> T) -> Self {
+        TaskLocal { init }
+    }
+
+    /// call f with the calling task's instance, creating it if it's the first access. Outside
+    /// spawned tasks, the base thread of the runtime has its own instance. It panics if no
+    /// runtime is initialized on the calling OS thread.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.try_with(f) {
+            Ok(result) => result,
+            Err(err) => panic!("failed to access a task local: {}", err),
+        }
+    }
+
+    /// try_with is like with, but fails with Error::NotInitialized instead of panicking if no
+    /// runtime is initialized on the calling OS thread.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let key = self as *const TaskLocal<T> as usize;
+        let value = with_locals(|locals| {
+            let value: &dyn Any = &**locals.entry(key).or_insert_with(|| Box::new((self.init)()));
+            value.downcast_ref::<T>().unwrap() as *const T
+        })?;
+        // the value is boxed, so it stays in place even if f accesses other task locals, and
+        // it's only dropped once the task finishes.
+        Ok(f(unsafe { &*value }))
+    }
+}
+
+/// task_local declares statics of type TaskLocal, e.g.
+///
+/// ```
+/// use std::cell::Cell;
+///
+/// green_threads::task_local! {
+///     static REQUEST_ID: Cell<u64> = Cell::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::TaskLocal<$t> = $crate::TaskLocal::new(|| $init);
+        )*
+    };
+}
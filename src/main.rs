@@ -1,231 +0,0 @@
-//! green-threads is a toy implementation on user-space threads in non-preemptive multitasking.
-//! This implementation is mostly guided by cfsamson's tutorial:
-//! https://cfsamson.gitbook.io/green-threads-explained-in-200-lines-of-rust/green-threads.
-#![deny(missing_docs)]
-#![feature(llvm_asm)]
-#![feature(naked_functions)]
-
-use std::ptr;
-
-const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 2;
-const MAX_THREADS: usize = 4;
-static mut RUNTIME: usize = 0;
-
-/// Runtime schedule and switch threads. current is the id of thread which is currently running.
-pub struct Runtime {
-    threads: Vec<Thread>,
-    current: usize,
-}
-
-#[derive(PartialEq, Eq, Debug)]
-enum State {
-    // available and ready to be assigned a task if needed
-    Available,
-    // running
-    Running,
-    // ready to move forward and resume execution
-    Ready,
-}
-
-/// ThreadContext contains the registers marked as "callee-saved" (preserved across calls)
-/// in the specification of x86-64 architecture. They contain all the information
-/// we need to resume a thread.
-#[derive(Debug, Default)]
-#[repr(C)]
-struct ThreadContext {
-    rsp: u64,
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    rbx: u64,
-    rbp: u64,
-}
-
-struct Thread {
-    id: usize,
-    stack: Vec<u8>,
-    ctx: ThreadContext,
-    state: State,
-}
-
-impl Thread {
-    fn new(id: usize) -> Self {
-        Thread {
-            id,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
-            ctx: ThreadContext::default(),
-            state: State::Available,
-        }
-    }
-
-    fn new_with_state(id: usize, state: State) -> Self {
-        Thread {
-            id,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
-            ctx: ThreadContext::default(),
-            state,
-        }
-    }
-}
-
-impl Runtime {
-    /// Initialize with a base thread.
-    pub fn new() -> Self {
-        let base_thread_id = 0;
-        let base_thread = Thread::new_with_state(base_thread_id, State::Running);
-
-        let mut threads = vec![base_thread];
-        let mut available_threads = (1..MAX_THREADS).map(|i| Thread::new(i)).collect();
-        threads.append(&mut available_threads);
-
-        Runtime {
-            threads,
-            current: base_thread_id,
-        }
-    }
-
-    /// This is cheating a bit, but we need a pointer to our Runtime
-    /// stored so we can call yield on it even if we don't have a
-    /// reference to it.
-    pub fn init(&self) {
-        unsafe {
-            let r_ptr: *const Runtime = self;
-            RUNTIME = r_ptr as usize;
-        }
-    }
-
-    /// start the runtime
-    pub fn run(&mut self) -> ! {
-        while self.t_yield() {}
-        std::process::exit(0);
-    }
-
-    fn t_return(&mut self) {
-        if self.current != 0 {
-            self.threads[self.current].state = State::Available;
-            self.t_yield();
-        }
-    }
-
-    fn t_yield(&mut self) -> bool {
-        let mut pos = self.current;
-        while self.threads[pos].state != State::Ready {
-            pos += 1;
-            if pos == self.threads.len() {
-                pos = 0;
-            }
-            if pos == self.current {
-                return false;
-            }
-        }
-
-        if self.threads[self.current].state != State::Available {
-            self.threads[self.current].state = State::Ready;
-        }
-
-        self.threads[pos].state = State::Running;
-        let old_pos = self.current;
-        self.current = pos;
-
-        unsafe {
-            switch(&mut self.threads[old_pos].ctx, &self.threads[pos].ctx);
-        }
-        // Prevents compiler from optimizing our code away on Windows.
-        self.threads.len() > 0
-    }
-
-    /// spawn a function to be executed by runtime
-    pub fn spawn(&mut self, f: fn()) {
-        let available = self
-            .threads
-            .iter_mut()
-            .find(|t| t.state == State::Available)
-            .expect("no available thread.");
-
-        let size = available.stack.len();
-        let s_ptr = available.stack.as_mut_ptr();
-
-        unsafe {
-            // put the f to the 16 bytes aligned position.
-            ptr::write(s_ptr.offset((size - 32) as isize) as *mut u64, f as u64);
-            // skip 8 bytes on stack for 16-bytes alignment while guard is running.
-            ptr::write(s_ptr.offset((size - 24) as isize) as *mut u64, skip as u64);
-            // put the guard next to the skip for being executed after skip returned.
-            ptr::write(s_ptr.offset((size - 16) as isize) as *mut u64, guard as u64);
-
-            available.ctx.rsp = s_ptr.offset((size - 32) as isize) as u64;
-        }
-        available.state = State::Ready;
-    }
-}
-
-fn skip() {
-}
-
-fn guard() {
-    unsafe {
-        let rt_ptr = RUNTIME as *mut Runtime;
-        (*rt_ptr).t_return();
-    }
-}
-
-/// yield_thread is a helper function that lets us call yield from an arbitrary place in our code.
-pub fn yield_thread() {
-    unsafe {
-        let rt_ptr = RUNTIME as *mut Runtime;
-        (*rt_ptr).t_yield();
-    };
-}
-
-#[naked]
-#[inline(never)]
-unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
-    llvm_asm!("
-        mov     %rsp, 0x00($0)
-        mov     %r15, 0x08($0)
-        mov     %r14, 0x10($0)
-        mov     %r13, 0x18($0)
-        mov     %r12, 0x20($0)
-        mov     %rbx, 0x28($0)
-        mov     %rbp, 0x30($0)
-
-        mov     0x00($1), %rsp
-        mov     0x08($1), %r15
-        mov     0x10($1), %r14
-        mov     0x18($1), %r13
-        mov     0x20($1), %r12
-        mov     0x28($1), %rbx
-        mov     0x30($1), %rbp
-        ret
-        "
-    :
-    :"r"(old), "r"(new)
-    :
-    : "volatile", "alignstack"
-    );
-}
-
-fn main() {
-    let mut runtime = Runtime::new();
-    runtime.init();
-    runtime.spawn(|| {
-        println!("THREAD 1 STARTING");
-        let id = 1;
-        for i in 0..10 {
-            println!("thread: {} counter: {}", id, i);
-            yield_thread();
-        }
-        println!("THREAD 1 FINISHED");
-    });
-    runtime.spawn(|| {
-        println!("THREAD 2 STARTING");
-        let id = 2;
-        for i in 0..15 {
-            println!("thread: {} counter: {}", id, i);
-            yield_thread();
-        }
-        println!("THREAD 2 FINISHED");
-    });
-    runtime.run();
-}
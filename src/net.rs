@@ -0,0 +1,340 @@
+//! net holds TCP sockets for green threads on Linux. Their sockets are non-blocking, and a task
+//! whose read, write or accept would block parks until its socket is ready, so it isn't
+//! scheduled at all in the meantime. A reactor on its own OS thread watches the sockets with
+//! epoll and unparks the waiting tasks through a Handle of their runtime, which also keeps run
+//! from returning while a task waits for a socket. Outside a task, e.g. on the base thread, the
+//! calling OS thread waits like JoinHandle::join does instead.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use crate::runtime::{consume_budget, current_task, in_runtime, park, spawn_blocking, Handle};
+use crate::task::{wait, TaskId};
+
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLRDHUP: u32 = 0x2000;
+const EPOLLONESHOT: u32 = 1 << 30;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+const EPOLL_CLOEXEC: i32 = 0x80000;
+const EEXIST: i32 = 17;
+const EINTR: i32 = 4;
+const MAX_EVENTS: usize = 64;
+
+// the struct epoll_event of the kernel ABI, which is packed on x86_64 only.
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+extern "C" {
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut EpollEvent, maxevents: i32, timeout: i32) -> i32;
+}
+
+static REACTOR: OnceLock<Reactor> = OnceLock::new();
+
+// Reactor is started by the first socket which would block, and runs for the rest of the
+// process. A socket registers the waiters to unpark together with what it waits for, and the
+// registration is armed once: after an event, the socket is registered again by the next
+// operation which would block. epoll is level-triggered, so a socket which got ready before it's
+// registered again reports right away, and no wakeup is lost in between.
+struct Reactor {
+    epfd: i32,
+    state: Mutex<ReactorState>,
+}
+
+#[derive(Default)]
+struct ReactorState {
+    // the sockets added to epoll, which are modified rather than added again
+    added: HashSet<i32>,
+    waiting: HashMap<i32, Interest>,
+}
+
+struct Interest {
+    events: u32,
+    waiters: Vec<Waiter>,
+}
+
+// Waiter is what waits for a socket: a parked task, unparked through a Handle of its runtime, or
+// an OS thread outside a task.
+enum Waiter {
+    Task(TaskId, Handle),
+    Thread(thread::Thread),
+}
+
+impl Waiter {
+    fn is(&self, other: &Waiter) -> bool {
+        match (self, other) {
+            (Waiter::Task(id, _), Waiter::Task(other, _)) => id == other,
+            (Waiter::Thread(thread), Waiter::Thread(other)) => thread.id() == other.id(),
+            _ => false,
+        }
+    }
+
+    fn wake(self) {
+        match self {
+            Waiter::Task(id, handle) => handle.unpark(id),
+            Waiter::Thread(thread) => thread.unpark(),
+        }
+    }
+}
+
+impl Reactor {
+    fn get() -> &'static Reactor {
+        REACTOR.get_or_init(|| {
+            let epfd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+            assert!(
+                epfd >= 0,
+                "failed to create the epoll instance of the reactor"
+            );
+            thread::Builder::new()
+                .name("green-threads-reactor".to_string())
+                .spawn(move || poll_events(epfd))
+                .expect("failed to spawn the reactor");
+            Reactor {
+                epfd,
+                state: Mutex::new(ReactorState::default()),
+            }
+        })
+    }
+
+    // register waiter to be unparked once fd is ready for events.
+    fn register(&self, fd: i32, events: u32, waiter: Waiter) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let interest = state.waiting.entry(fd).or_insert(Interest {
+            events: 0,
+            waiters: Vec::new(),
+        });
+        interest.events |= events;
+        if interest.waiters.iter().all(|w| !w.is(&waiter)) {
+            interest.waiters.push(waiter);
+        }
+        let mut event = EpollEvent {
+            events: interest.events | EPOLLRDHUP | EPOLLONESHOT,
+            data: fd as u64,
+        };
+        let op = if state.added.insert(fd) {
+            EPOLL_CTL_ADD
+        } else {
+            EPOLL_CTL_MOD
+        };
+        let mut result = unsafe { epoll_ctl(self.epfd, op, fd, &mut event) };
+        if result < 0 && io::Error::last_os_error().raw_os_error() == Some(EEXIST) {
+            result = unsafe { epoll_ctl(self.epfd, EPOLL_CTL_MOD, fd, &mut event) };
+        }
+        if result < 0 {
+            state.waiting.remove(&fd);
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // forget the task id waiting for fd.
+    fn unregister(&self, fd: i32, id: TaskId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(interest) = state.waiting.get_mut(&fd) {
+            interest
+                .waiters
+                .retain(|w| !matches!(w, Waiter::Task(task, _) if *task == id));
+        }
+    }
+
+    // forget fd before it's closed, since the OS reuses its number.
+    fn deregister(&self, fd: i32) {
+        let mut state = self.state.lock().unwrap();
+        if state.added.remove(&fd) {
+            unsafe { epoll_ctl(self.epfd, EPOLL_CTL_DEL, fd, ptr::null_mut()) };
+        }
+        state.waiting.remove(&fd);
+    }
+
+    // unpark the waiters of fd, which try their operation again.
+    fn wake(&self, fd: i32) {
+        let interest = self.state.lock().unwrap().waiting.remove(&fd);
+        for waiter in interest.into_iter().flat_map(|interest| interest.waiters) {
+            waiter.wake();
+        }
+    }
+}
+
+// poll_events is the loop of the reactor's OS thread.
+fn poll_events(epfd: i32) {
+    let mut events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+    loop {
+        let n = unsafe { epoll_wait(epfd, events.as_mut_ptr(), MAX_EVENTS as i32, -1) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            assert!(err.raw_os_error() == Some(EINTR), "reactor failed: {}", err);
+            continue;
+        }
+        let reactor = Reactor::get();
+        for event in &events[..n as usize] {
+            reactor.wake(event.data as i32);
+        }
+    }
+}
+
+// io_wait retries op until it doesn't fail with WouldBlock, waiting for fd to be ready for
+// events in between: a task parks until the reactor unparks it, and anything else waits like
+// JoinHandle::join does.
+fn io_wait<T>(fd: i32, events: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    consume_budget();
+    let mut registered: Option<Registered> = None;
+    loop {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+        let registered = match &registered {
+            Some(registered) => registered,
+            None => match current_task() {
+                Some((id, handle)) => registered.insert(Registered { fd, id, handle }),
+                None => return thread_wait(fd, events, op),
+            },
+        };
+        let waiter = Waiter::Task(registered.id, registered.handle.clone());
+        Reactor::get().register(fd, events, waiter)?;
+        // park may return before the socket is ready, in which case op fails again.
+        park();
+    }
+}
+
+// thread_wait is io_wait outside a task, where the OS thread is unparked instead.
+fn thread_wait<T>(fd: i32, events: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    wait(|| match op() {
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            let waiter = Waiter::Thread(thread::current());
+            match Reactor::get().register(fd, events, waiter) {
+                Ok(()) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }
+        result => Some(result),
+    })
+}
+
+// Registered is a task waiting for a socket, which is taken off it however the task stops
+// waiting, unwinding included, so a Handle left behind doesn't keep the runtime running.
+struct Registered {
+    fd: i32,
+    id: TaskId,
+    handle: Handle,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        Reactor::get().unregister(self.fd, self.id);
+    }
+}
+
+/// TcpListener is a TCP socket listening for connections, like std::net::TcpListener, whose
+/// accept waits for a connection without blocking the runtime.
+pub struct TcpListener {
+    inner: net::TcpListener,
+}
+
+impl TcpListener {
+    /// bind a listener to addr, which doesn't block.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let inner = net::TcpListener::bind(addr)?;
+        inner.set_nonblocking(true)?;
+        Ok(TcpListener { inner })
+    }
+
+    /// Accept a connection, and return it with the address of its peer. It yields to other
+    /// threads until a connection comes in.
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = io_wait(self.inner.as_raw_fd(), EPOLLIN, || self.inner.accept())?;
+        stream.set_nonblocking(true)?;
+        Ok((TcpStream { inner: stream }, addr))
+    }
+
+    /// the address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Some(reactor) = REACTOR.get() {
+            reactor.deregister(self.inner.as_raw_fd());
+        }
+    }
+}
+
+/// TcpStream is a TCP connection, like std::net::TcpStream, whose reads and writes wait for the
+/// socket to be ready without blocking the runtime.
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    /// Connect to addr. On a runtime, the connection is made on an OS thread of spawn_blocking,
+    /// so other threads keep running in the meantime. Elsewhere it blocks.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let inner = if in_runtime() {
+            spawn_blocking(move || net::TcpStream::connect(&addrs[..]))
+                .join()
+                .map_err(|err| io::Error::other(err.to_string()))??
+        } else {
+            net::TcpStream::connect(&addrs[..])?
+        };
+        inner.set_nonblocking(true)?;
+        Ok(TcpStream { inner })
+    }
+
+    /// the address of the peer of the connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// the address of the local end of the connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// shut down the reading half, the writing half or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.inner.as_raw_fd();
+        io_wait(fd, EPOLLIN, || self.inner.read(buf))
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.inner.as_raw_fd();
+        io_wait(fd, EPOLLOUT, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if let Some(reactor) = REACTOR.get() {
+            reactor.deregister(self.inner.as_raw_fd());
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! preempt holds the time slices of a runtime built with RuntimeBuilder::preemption. A timer on
+//! its own OS thread requests the running thread to yield once it has run for a whole slice,
+//! which it does at its next preemption point. Nothing is interrupted: a thread which never
+//! reaches a preemption point still runs until it yields.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Timer ticks every slice, and requests a yield if the runtime hasn't switched threads since
+/// the previous tick, so a thread gets between one and two slices before it's asked to yield.
+/// Dropping the timer stops its OS thread.
+pub(crate) struct Timer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    // bumped by the runtime on every switch, so the timer can tell whether a slice went by
+    // without one
+    switches: AtomicU64,
+    requested: AtomicBool,
+    stopped: Mutex<bool>,
+    // notified when the timer is dropped
+    condvar: Condvar,
+}
+
+impl Timer {
+    pub(crate) fn new(slice: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            switches: AtomicU64::new(0),
+            requested: AtomicBool::new(false),
+            stopped: Mutex::new(false),
+            condvar: Condvar::new(),
+        });
+        let ticking = shared.clone();
+        thread::Builder::new()
+            .name("green-threads-timer".to_string())
+            .spawn(move || tick(ticking, slice))
+            .expect("failed to spawn the preemption timer");
+        Timer { shared }
+    }
+
+    // start a new slice for the thread switched to.
+    pub(crate) fn switched(&self) {
+        self.shared.switches.fetch_add(1, Ordering::Relaxed);
+        self.shared.requested.store(false, Ordering::Relaxed);
+    }
+
+    // whether the running thread has used up its slice.
+    pub(crate) fn expired(&self) -> bool {
+        self.shared.requested.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.condvar.notify_one();
+    }
+}
+
+// tick runs on the timer's OS thread until the timer is dropped.
+fn tick(shared: Arc<Shared>, slice: Duration) {
+    let mut last = shared.switches.load(Ordering::Relaxed);
+    let mut stopped = shared.stopped.lock().unwrap();
+    while !*stopped {
+        stopped = shared.condvar.wait_timeout(stopped, slice).unwrap().0;
+        let switches = shared.switches.load(Ordering::Relaxed);
+        if switches == last {
+            shared.requested.store(true, Ordering::Relaxed);
+        }
+        last = switches;
+    }
+}
+
+/// preemption_point yields if the calling thread has used up its time slice, like the
+/// preemption_point function, e.g.
+///
+/// ```
+/// fn checksum(data: &[u8]) -> u32 {
+///     let mut sum = 0_u32;
+///     for chunk in data.chunks(4096) {
+///         sum = chunk.iter().fold(sum, |sum, &b| sum.wrapping_mul(31).wrapping_add(b as u32));
+///         green_threads::preemption_point!();
+///     }
+///     sum
+/// }
+/// ```
+#[macro_export]
+macro_rules! preemption_point {
+    () => {
+        $crate::preemption_point()
+    };
+}
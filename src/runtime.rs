@@ -0,0 +1,2813 @@
+//! runtime holds the scheduler: the threads it owns, how tasks are spawned on them, and the
+//! functions tasks call to yield to each other.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::cmp::{self, Reverse};
+use std::collections::binary_heap::PeekMut;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::arch::{switch, ThreadContext};
+use crate::blocking::BlockingPool;
+use crate::future;
+use crate::preempt::Timer;
+use crate::stack::Stack;
+use crate::task::{wait, Cancelled, Completion, JoinError, JoinHandle, Packet, TaskId};
+
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 2;
+const MIN_STACK_SIZE: usize = 1024 * 4;
+const MAX_THREADS: usize = 4;
+const MAX_POOLED_STACKS: usize = 16;
+const MAX_LIFO_RUNS: usize = 3;
+const BUDGET: usize = 128;
+// what stacks are filled with to measure how much of them a task used
+const STACK_PATTERN: u8 = 0xa5;
+// whether task stacks get canaries, see Thread::check_canaries: always in debug builds, and in
+// release builds only with the stack-canaries feature
+const CANARIES: bool = cfg!(any(debug_assertions, feature = "stack-canaries"));
+const STACK_CANARY: u64 = 0x5afe_57ac_c0de_d00d;
+const CANARY_LEN: usize = mem::size_of::<u64>();
+// the bytes left free at the top of a task's stack, above the trampoline slots, for the high
+// canary. None of a task's frames reach above the slots on any architecture.
+const CANARY_GAP: usize = if CANARIES { 16 } else { 0 };
+const MAX_BLOCKING_THREADS: usize = 8;
+const BLOCKING_KEEP_ALIVE: Duration = Duration::from_secs(10);
+static PANIC_HOOK: Once = Once::new();
+
+thread_local! {
+    // the runtime initialized on this OS thread, which we yield on, or null if there is none.
+    // Each OS thread has its own, so runtimes on different OS threads don't see each other.
+    static RUNTIME: Cell<*mut Runtime> = const { Cell::new(ptr::null_mut()) };
+    // the Remote of that runtime, which tells it apart from the others even once it's been moved.
+    static REMOTE: Cell<*const Remote> = const { Cell::new(ptr::null()) };
+}
+
+/// Runtime schedule and switch threads. current is the id of thread which is currently running.
+///
+/// The Runtime owns every thread with its stack, including the one currently running, which is
+/// only borrowed by switching onto its stack. A thread's stack is only allocated once it's given
+/// its first task, and a finished thread stays available with its stack for the next spawn.
+/// Everything is freed when the Runtime is dropped.
+pub struct Runtime {
+    threads: Vec<Thread>,
+    current: usize,
+    default_stack_size: usize,
+    // whether init registered this runtime as the one to yield on
+    initialized: Cell<bool>,
+    // set by shutdown until run returns
+    stopping: Cell<bool>,
+    // stacks replaced by one of another size, kept for spawns asking for their size again
+    stack_pool: Vec<Stack>,
+    // the deadlines of sleeping threads with their position, the earliest first
+    timers: BinaryHeap<Reverse<(Instant, usize)>>,
+    // the OS threads running the closures of spawn_blocking
+    blocking: BlockingPool,
+    // tasks spawned while all the threads were taken, waiting for one to be available, with a
+    // queue per priority indexed by Priority
+    queues: [VecDeque<Queued>; 3],
+    // tasks spawned by spawn_after, queued once their deadline passes, the earliest first
+    delayed: BinaryHeap<Reverse<Delayed>>,
+    // the thread started by a running task or woken up last, which runs next ahead of the round
+    // robin order, and how many times in a row it's been done so far
+    lifo: Option<usize>,
+    lifo_runs: usize,
+    // the thread yield_to asked to run next, whatever its priority
+    next: Option<usize>,
+    // the thread yielding with yield_thread_front, which runs again as soon as the thread it
+    // yields to yields in turn, and the thread it yielded to, after which the round robin order
+    // goes on once the caller yields again rather than after the caller
+    give_back: Option<usize>,
+    cursor: Option<usize>,
+    // how many times in a row threads yielded from t_wait with nothing happening in between
+    idle_yields: usize,
+    // how many times t_yield switched to a thread other than the base thread, see
+    // yield_switched
+    task_switches: u64,
+    // how many more operations on runtime primitives the running thread gets before it yields,
+    // see consume_budget
+    budget: usize,
+    // the timer ending the time slices of threads, if preemption is enabled
+    timer: Option<Timer>,
+    // how many times a ready thread or queue of a lower priority can be passed over before it
+    // goes first, if aging is enabled, and how many times each queue has been so far
+    aging: Option<usize>,
+    queue_ages: [usize; 3],
+    // how many tasks can be alive at the same time, if they're limited
+    max_tasks: Option<usize>,
+    // whether stacks are filled with STACK_PATTERN to measure how much of them tasks use
+    measure_stack_usage: bool,
+    // whether stacks are backed by huge pages, see RuntimeBuilder::huge_page_stacks
+    huge_page_stacks: bool,
+    // the tasks unparked while they weren't parked, which don't park the next time they try
+    unparked: HashSet<TaskId>,
+    stats: RuntimeStats,
+    // what the runtime shares with its Handles
+    remote: Arc<Remote>,
+    // how many tasks spawned by Handles were waiting to be queued when pending_tasks last
+    // looked
+    last_injected: Cell<usize>,
+}
+
+/// RuntimeStats counts what the scheduler of a Runtime has done since it was created.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct RuntimeStats {
+    /// the number of tasks spawned, including the ones still queued.
+    pub spawned: u64,
+    /// the number of tasks which finished, whether they returned, panicked or were cancelled
+    /// while running. Tasks removed before they started aren't counted.
+    pub completed: u64,
+    /// the number of times a thread yielded to another one.
+    pub yields: u64,
+    /// the number of tasks dropped before they started, because they were aborted, the runtime
+    /// was shut down, or they were spawned through a Handle beyond RuntimeBuilder::max_tasks.
+    pub abandoned: u64,
+    /// the number of stacks allocated. A finished thread keeps its stack for the next task, and
+    /// a stack replaced by one of another size is pooled, so this only grows with the number of
+    /// threads alive at the same time and the stack sizes they ask for.
+    pub stacks: u64,
+    /// the number of tasks which got a stack another task ran on before, kept by their thread
+    /// or taken from the pool, instead of allocating one.
+    pub stacks_reused: u64,
+    /// the number of stacks freed because the pool was full.
+    pub stacks_freed: u64,
+    /// the number of tasks whose stack usage was measured, if the runtime is built with
+    /// RuntimeBuilder::measure_stack_usage.
+    pub stacks_measured: u64,
+    /// the sum of the bytes of their stack the measured tasks used.
+    pub total_stack_used: u64,
+    /// the most bytes of its stack any measured task used.
+    pub max_stack_used: u64,
+}
+
+impl RuntimeStats {
+    /// the mean number of bytes of their stack the measured tasks used, or None if none was.
+    pub fn mean_stack_used(&self) -> Option<u64> {
+        self.total_stack_used.checked_div(self.stacks_measured)
+    }
+
+    /// the number of stacks allocated and not freed yet, whether a thread or the pool holds them.
+    pub fn live_stacks(&self) -> u64 {
+        self.stacks - self.stacks_freed
+    }
+}
+
+/// RuntimeBuilder configures a Runtime before it's created.
+pub struct RuntimeBuilder {
+    max_threads: usize,
+    default_stack_size: usize,
+    max_blocking_threads: usize,
+    blocking_keep_alive: Duration,
+    preemption: Option<Duration>,
+    aging: Option<usize>,
+    max_tasks: Option<usize>,
+    measure_stack_usage: bool,
+    huge_page_stacks: bool,
+}
+
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        RuntimeBuilder {
+            max_threads: MAX_THREADS - 1,
+            default_stack_size: DEFAULT_STACK_SIZE,
+            max_blocking_threads: MAX_BLOCKING_THREADS,
+            blocking_keep_alive: BLOCKING_KEEP_ALIVE,
+            preemption: None,
+            aging: None,
+            max_tasks: None,
+            measure_stack_usage: false,
+            huge_page_stacks: false,
+        }
+    }
+}
+
+impl RuntimeBuilder {
+    /// the number of spawned threads that can be alive at the same time, not counting the base
+    /// thread. Tasks spawned beyond it are queued until a thread finishes, except when every
+    /// thread waits while tasks are queued, e.g. because they all join children which are still
+    /// queued: then a thread is added for the queued tasks instead of deadlocking, and kept for
+    /// later. Panics if n is 0.
+    pub fn max_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "the runtime needs at least one thread.");
+        self.max_threads = n;
+        self
+    }
+
+    /// the stack size of threads spawned without an explicit one. Panics if it's less than 4 KiB.
+    pub fn default_stack_size(mut self, size: usize) -> Self {
+        assert!(size >= MIN_STACK_SIZE, "{}", Error::StackTooSmall(size));
+        self.default_stack_size = (size + 15) & !15;
+        self
+    }
+
+    /// the number of OS threads running the closures of spawn_blocking at the same time. They're
+    /// started as needed. Panics if n is 0.
+    pub fn max_blocking_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "the blocking pool needs at least one thread.");
+        self.max_blocking_threads = n;
+        self
+    }
+
+    /// how long an OS thread of spawn_blocking waits for another closure before it exits.
+    pub fn blocking_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.blocking_keep_alive = keep_alive;
+        self
+    }
+
+    /// Enable preemption with time slices of slice. A thread which has run for a whole slice
+    /// without yielding is asked to, and yields at its next preemption point: a call to
+    /// preemption_point, or an operation on a runtime primitive, see consume_budget. It can't
+    /// be interrupted anywhere else, so a loop calling neither still runs until it's done. The
+    /// slices are measured by a timer on an OS thread of its own rather than by a signal, so it
+    /// doesn't interrupt system calls, and there's nothing to be careful about when running
+    /// between preemption points. Disabled by default.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// use green_threads::{preemption_point, Runtime};
+    ///
+    /// let mut runtime = Runtime::builder()
+    ///     .preemption(Duration::from_millis(1))
+    ///     .build();
+    /// runtime.init();
+    /// let done = Arc::new(AtomicBool::new(false));
+    /// let stop = done.clone();
+    /// // never yields, but gets preempted so the other thread can stop it.
+    /// runtime.spawn(move || {
+    ///     while !done.load(Ordering::Relaxed) {
+    ///         preemption_point!();
+    ///     }
+    /// });
+    /// runtime.spawn(move || stop.store(true, Ordering::Relaxed));
+    /// runtime.run();
+    /// ```
+    pub fn preemption(mut self, slice: Duration) -> Self {
+        self.preemption = Some(slice);
+        self
+    }
+
+    /// Enable aging, so that threads of a lower priority don't starve while threads of a higher
+    /// one keep being ready. Every time the runtime picks a thread to run, the ready threads of
+    /// a lower priority get older, and once one of them has been passed over rounds times, it
+    /// runs ahead of the others as if it had their priority, which makes it young again. The
+    /// queues of tasks waiting for a thread age the same way whenever a task of a higher
+    /// priority is started, and then the oldest task of the queue starts first. Disabled by
+    /// default. Panics if rounds is 0.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use green_threads::{yield_thread, Priority, Runtime, TaskBuilder};
+    ///
+    /// let mut runtime = Runtime::builder().aging(8).build();
+    /// runtime.init();
+    /// let done = Arc::new(AtomicBool::new(false));
+    /// let low = done.clone();
+    /// runtime.spawn_with_priority(Priority::Low, move || low.store(true, Ordering::Relaxed));
+    /// // keeps spawning threads of a higher priority, which would starve the low one forever.
+    /// let rounds = runtime.spawn_with_priority(Priority::High, move || {
+    ///     let mut rounds = 0;
+    ///     while !done.load(Ordering::Relaxed) {
+    ///         let high = TaskBuilder::new().priority(Priority::High);
+    ///         high.spawn(yield_thread).unwrap();
+    ///         yield_thread();
+    ///         rounds += 1;
+    ///     }
+    ///     rounds
+    /// });
+    /// runtime.run();
+    /// assert!(rounds.join().unwrap() <= 8);
+    /// ```
+    pub fn aging(mut self, rounds: usize) -> Self {
+        assert!(rounds > 0, "aging needs at least one round.");
+        self.aging = Some(rounds);
+        self
+    }
+
+    /// Limit the number of tasks alive at the same time, i.e. spawned but not finished yet,
+    /// whether they're running or still waiting for a thread, e.g. for backpressure on a server
+    /// spawning a task per connection. Once the limit is reached, try_spawn and
+    /// TaskBuilder::spawn fail with Error::TooManyTasks until a task finishes, and the spawns
+    /// which can't fail panic, except for Handle::spawn, whose task is dropped once the runtime
+    /// gets to queue it, so that its join returns JoinError::Cancelled. Unlimited by default.
+    /// Panics if n is 0.
+    ///
+    /// ```
+    /// use green_threads::{try_spawn, Error, Runtime};
+    ///
+    /// let mut runtime = Runtime::builder().max_tasks(2).build();
+    /// runtime.init();
+    /// let first = try_spawn(|| 1).unwrap();
+    /// let second = try_spawn(|| 2).unwrap();
+    /// assert!(matches!(try_spawn(|| 3), Err(Error::TooManyTasks(2))));
+    /// assert_eq!(first.join().unwrap(), 1);
+    /// let third = try_spawn(|| 3).unwrap();
+    /// assert_eq!(second.join().unwrap() + third.join().unwrap(), 5);
+    /// ```
+    pub fn max_tasks(mut self, n: usize) -> Self {
+        assert!(n > 0, "the runtime needs to allow at least one task.");
+        self.max_tasks = Some(n);
+        self
+    }
+
+    /// Measure how much of its stack every task uses, to help picking stack sizes. The stack of
+    /// a task is filled with a pattern before it starts, and once it finishes, the part which
+    /// doesn't hold the pattern anymore is what it used, which JoinHandle::stack_used returns
+    /// and RuntimeStats sums up. Filling a stack takes time and commits all of its pages, so
+    /// it's disabled by default. Threads of spawn_with_arg aren't measured.
+    ///
+    /// ```
+    /// use std::hint::black_box;
+    ///
+    /// use green_threads::Runtime;
+    ///
+    /// let mut runtime = Runtime::builder().measure_stack_usage(true).build();
+    /// runtime.init();
+    /// let handle = runtime.spawn(|| {
+    ///     let buffer = black_box([1_u8; 64 * 1024]);
+    ///     buffer.iter().map(|&b| b as usize).sum::<usize>()
+    /// });
+    /// runtime.run();
+    /// let used = handle.stack_used().unwrap();
+    /// assert!(used >= 64 * 1024 && used < 1024 * 1024);
+    /// assert_eq!(runtime.stats().max_stack_used, used as u64);
+    /// assert_eq!(handle.join().unwrap(), 64 * 1024);
+    /// ```
+    pub fn measure_stack_usage(mut self, enabled: bool) -> Self {
+        self.measure_stack_usage = enabled;
+        self
+    }
+
+    /// Back the stacks of threads with transparent huge pages on Linux, for workloads with many
+    /// long-lived threads with large stacks, where the TLB misses of regular pages add up. Each
+    /// stack takes whole huge pages of 2 MiB, which are committed one at a time when touched,
+    /// so a thread costs at least 2 MiB of memory rather than the few KiB it uses. The guard
+    /// page below a stack stays a regular page. If the kernel has transparent huge pages
+    /// disabled, the stacks just get regular pages. Other platforms ignore it. Disabled by
+    /// default.
+    ///
+    /// ```
+    /// use green_threads::Runtime;
+    ///
+    /// let mut runtime = Runtime::builder().huge_page_stacks(true).build();
+    /// runtime.init();
+    /// let handle = runtime.spawn(|| {
+    ///     let local = 0_u8;
+    ///     let addr = &local as *const u8 as usize;
+    ///     // the AnonHugePages of the mapping the stack of this thread is in.
+    ///     let smaps = std::fs::read_to_string("/proc/self/smaps").ok()?;
+    ///     let mut lines = smaps.lines();
+    ///     while let Some(line) = lines.next() {
+    ///         let range = line.split(' ').next()?;
+    ///         let (start, end) = match range.split_once('-') {
+    ///             Some((start, end)) => (start, end),
+    ///             None => continue,
+    ///         };
+    ///         let start = usize::from_str_radix(start, 16).ok()?;
+    ///         let end = usize::from_str_radix(end, 16).ok()?;
+    ///         if (start..end).contains(&addr) {
+    ///             let huge = lines.find(|l| l.starts_with("AnonHugePages:"))?;
+    ///             return huge.split_whitespace().nth(1)?.parse::<usize>().ok();
+    ///         }
+    ///     }
+    ///     None
+    /// });
+    /// runtime.run();
+    /// let huge_kib = handle.join().unwrap();
+    /// #[cfg(target_os = "linux")]
+    /// {
+    ///     let thp = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled");
+    ///     if thp.is_ok_and(|thp| !thp.contains("[never]")) {
+    ///         assert_eq!(huge_kib, Some(2048));
+    ///     }
+    /// }
+    /// # let _ = huge_kib;
+    /// ```
+    pub fn huge_page_stacks(mut self, enabled: bool) -> Self {
+        self.huge_page_stacks = enabled;
+        self
+    }
+
+    /// create the Runtime with a base thread.
+    pub fn build(self) -> Runtime {
+        let base_thread_id = 0;
+        let base_thread = Thread::new_with_state(base_thread_id, State::Running);
+
+        let mut threads = vec![base_thread];
+        let mut available_threads = (1..=self.max_threads).map(Thread::new).collect();
+        threads.append(&mut available_threads);
+
+        Runtime {
+            threads,
+            current: base_thread_id,
+            default_stack_size: self.default_stack_size,
+            initialized: Cell::new(false),
+            stopping: Cell::new(false),
+            stack_pool: Vec::new(),
+            timers: BinaryHeap::new(),
+            blocking: BlockingPool::new(self.max_blocking_threads, self.blocking_keep_alive),
+            queues: Default::default(),
+            delayed: BinaryHeap::new(),
+            lifo: None,
+            lifo_runs: 0,
+            next: None,
+            give_back: None,
+            cursor: None,
+            idle_yields: 0,
+            task_switches: 0,
+            budget: BUDGET,
+            timer: self.preemption.map(Timer::new),
+            aging: self.aging,
+            queue_ages: [0; 3],
+            max_tasks: self.max_tasks,
+            measure_stack_usage: self.measure_stack_usage,
+            huge_page_stacks: self.huge_page_stacks,
+            unparked: HashSet::new(),
+            stats: RuntimeStats::default(),
+            remote: Arc::new(Remote::default()),
+            last_injected: Cell::new(0),
+        }
+    }
+}
+
+/// TaskBuilder configures a task before it's spawned, to combine what spawn_named,
+/// spawn_with_stack, spawn_with_priority and spawn_with_deadline set one at a time.
+///
+/// ```
+/// use green_threads::{current_name, Priority, Runtime, TaskBuilder};
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let handle = TaskBuilder::new()
+///     .name("worker-3")
+///     .priority(Priority::High)
+///     .stack_size(64 * 1024)
+///     .spawn(|| current_name())
+///     .unwrap();
+/// runtime.run();
+/// assert_eq!(handle.join().unwrap().as_deref(), Some("worker-3"));
+/// ```
+#[derive(Default)]
+pub struct TaskBuilder {
+    name: Option<String>,
+    stack_size: Option<usize>,
+    priority: Priority,
+    deadline: Option<Instant>,
+}
+
+impl TaskBuilder {
+    /// a builder for an unnamed task with the default stack size and priority.
+    pub fn new() -> Self {
+        TaskBuilder::default()
+    }
+
+    /// the name of the task, which current_name returns and panic messages show.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// the stack size of the task, like Runtime::spawn_with_stack.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// the priority of the task, like Runtime::spawn_with_priority.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// the deadline of the task, like Runtime::spawn_with_deadline.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Spawn a closure with the configuration of the builder on the runtime initialized on the
+    /// calling OS thread, like the spawn function. It fails with Error::StackTooSmall if the
+    /// stack size is less than 4 KiB, Error::NotInitialized if no runtime is initialized on the
+    /// calling OS thread, Error::ShuttingDown if the runtime is shutting down, or
+    /// Error::TooManyTasks if the runtime has reached its max_tasks.
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let rt = unsafe { get_rt() }?;
+        rt.check_running()?;
+        rt.check_capacity()?;
+        let stack_size = match self.stack_size {
+            Some(size) if size < MIN_STACK_SIZE => return Err(Error::StackTooSmall(size)),
+            Some(size) => (size + 15) & !15,
+            None => rt.default_stack_size,
+        };
+        let (mut queued, handle) = unsafe { rt.new_task(self.name, stack_size, f) };
+        queued.priority = self.priority;
+        queued.deadline = self.deadline;
+        rt.push_queued(queued);
+        rt.start_queued();
+        Ok(handle)
+    }
+}
+
+/// Priority decides which thread runs first when several of them are ready. Threads of a lower
+/// priority can starve unless aging is enabled, see Runtime::spawn_with_priority.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+pub enum Priority {
+    /// runs only when nothing else is ready.
+    Low,
+    /// the priority of the threads spawned without one.
+    #[default]
+    Normal,
+    /// runs before anything else.
+    High,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum State {
+    // available and ready to be assigned a task if needed
+    Available,
+    // running
+    Running,
+    // ready to move forward and resume execution
+    Ready,
+    // waiting for its deadline in the runtime's timers to pass before it's ready again
+    Sleeping,
+    // waiting for unpark to make it ready again
+    Parked,
+}
+
+struct Thread {
+    id: usize,
+    // allocated when the thread first runs a task, and never for the base thread, which runs on
+    // the stack of the OS thread
+    stack: Option<Stack>,
+    // the stack size the task asked for, given to the thread right before the task starts
+    stack_size: usize,
+    ctx: ThreadContext,
+    state: State,
+    // the task running on this thread, or None for the base thread and available threads
+    task_id: Option<TaskId>,
+    // set by JoinHandle::abort to ask the task to stop
+    cancelled: Option<Arc<AtomicBool>>,
+    // the name given at spawn, or None if it's unnamed
+    name: Option<String>,
+    // the closure to run, taken out by call_task when the thread starts
+    task: Option<Box<dyn FnOnce() + 'static>>,
+    // the argument written on the stack by spawn_with_arg, taken out by call_arg_task
+    arg: Option<StackArg>,
+    // the values of the task locals the task has accessed, by the address of their TaskLocal
+    locals: HashMap<usize, Box<dyn Any>>,
+    priority: Priority,
+    // how many times in a row a thread of a higher priority was picked over this one while it
+    // was ready, see RuntimeBuilder::aging
+    age: usize,
+    // the deadline given at spawn, which orders the threads of the same priority
+    deadline: Option<Instant>,
+}
+
+// Queued is a spawned task which hasn't been given a thread yet.
+struct Queued {
+    task_id: TaskId,
+    priority: Priority,
+    deadline: Option<Instant>,
+    name: Option<String>,
+    stack_size: usize,
+    cancelled: Arc<AtomicBool>,
+    task: Box<dyn FnOnce() + 'static>,
+}
+
+// Delayed is a task spawned by spawn_after waiting for its deadline. seq orders the tasks with
+// the same deadline by their spawn.
+struct Delayed {
+    deadline: Instant,
+    seq: u64,
+    queued: Queued,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+/// Handle spawns tasks onto a Runtime from other OS threads, e.g. one accepting connections,
+/// while the Runtime runs elsewhere. A Runtime can't be shared, since run borrows it for as long
+/// as it runs, but a Handle can be cloned and sent anywhere. Spawned tasks are queued on the
+/// runtime the next time it schedules, and a runtime parked with nothing to do is woken up for
+/// them. run doesn't return while any Handle is alive, even if all tasks have finished, so that
+/// more of them can come in.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::{mpsc, Arc};
+/// use std::thread;
+///
+/// use green_threads::Runtime;
+///
+/// let (sender, receiver) = mpsc::channel();
+/// let background = thread::spawn(move || {
+///     let mut runtime = Runtime::new();
+///     runtime.init();
+///     sender.send(runtime.handle()).unwrap();
+///     runtime.run();
+///     runtime.stats().completed
+/// });
+/// let handle = receiver.recv().unwrap();
+/// let count = Arc::new(AtomicUsize::new(0));
+/// let tasks: Vec<_> = (0..100)
+///     .map(|_| {
+///         let count = count.clone();
+///         handle.spawn(move || count.fetch_add(1, Ordering::Relaxed))
+///     })
+///     .collect();
+/// for task in tasks {
+///     task.join().unwrap();
+/// }
+/// drop(handle);
+/// assert_eq!(background.join().unwrap(), 100);
+/// assert_eq!(count.load(Ordering::Relaxed), 100);
+/// ```
+pub struct Handle {
+    remote: Arc<Remote>,
+}
+
+// Remote is the part of a Runtime its Handles share: the tasks they spawned, waiting to be
+// queued, and the OS thread running the runtime, to unpark once there's something new.
+#[derive(Default)]
+struct Remote {
+    injected: Mutex<Vec<Injected>>,
+    thread: Mutex<Option<thread::Thread>>,
+    // the number of Handles alive, which keep run from returning
+    handles: AtomicUsize,
+    // set once the runtime is dropped, under the lock of injected
+    closed: AtomicBool,
+    // set by Handle::shutdown until the runtime sees it
+    stopping: AtomicBool,
+    // the tasks unparked by other OS threads, waiting for the runtime to unpark them
+    woken: Mutex<Vec<TaskId>>,
+}
+
+// Injected is a task spawned by a Handle, which becomes a Queued task once the runtime takes it.
+struct Injected {
+    task_id: TaskId,
+    cancelled: Arc<AtomicBool>,
+    task: Box<dyn FnOnce() + Send + 'static>,
+}
+
+/// StackArg points to an argument living on the top of a thread's stack, together with the
+/// function it's passed to and how to drop it if the thread never gets to run.
+struct StackArg {
+    ptr: *mut u8,
+    f: usize,
+    drop: unsafe fn(*mut u8),
+}
+
+impl Thread {
+    // the order in which ready threads run: the highest priority first, then the earliest
+    // deadline, and then the threads without one.
+    fn rank(&self) -> (Reverse<Priority>, bool, Option<Instant>) {
+        (
+            Reverse(self.priority),
+            self.deadline.is_none(),
+            self.deadline,
+        )
+    }
+
+    fn new(id: usize) -> Self {
+        Thread {
+            id,
+            stack: None,
+            stack_size: 0,
+            ctx: ThreadContext::default(),
+            state: State::Available,
+            task_id: None,
+            cancelled: None,
+            name: None,
+            task: None,
+            arg: None,
+            locals: HashMap::new(),
+            priority: Priority::Normal,
+            age: 0,
+            deadline: None,
+        }
+    }
+
+    fn new_with_state(id: usize, state: State) -> Self {
+        Thread {
+            id,
+            stack: None,
+            stack_size: 0,
+            ctx: ThreadContext::default(),
+            state,
+            task_id: None,
+            cancelled: None,
+            name: None,
+            task: None,
+            arg: None,
+            locals: HashMap::new(),
+            priority: Priority::Normal,
+            age: 0,
+            deadline: None,
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match &self.cancelled {
+            Some(cancelled) => cancelled.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    // write the canaries at the low end of the thread's stack and in the gap above its
+    // trampoline slots, right before its task is started.
+    fn write_canaries(&mut self) {
+        if let Some(stack) = self.stack.as_mut() {
+            let len = stack.len();
+            let canary = STACK_CANARY.to_ne_bytes();
+            stack[..CANARY_LEN].copy_from_slice(&canary);
+            stack[len - CANARY_LEN..].copy_from_slice(&canary);
+        }
+    }
+
+    // check_canaries panics if either canary of the task running on the thread was overwritten,
+    // which means the task wrote past the end of something on its stack, or below the stack
+    // itself. Checking at every switch catches it before another task runs into the damage.
+    // The canaries are written again before panicking, so the task fails only once.
+    fn check_canaries(&mut self) {
+        if !CANARIES {
+            return;
+        }
+        let (id, stack) = match (self.task_id, self.stack.as_ref()) {
+            (Some(id), Some(stack)) => (id, stack),
+            _ => return,
+        };
+        let canary = STACK_CANARY.to_ne_bytes();
+        let low = stack[..CANARY_LEN] == canary;
+        let high = stack[stack.len() - CANARY_LEN..] == canary;
+        let end = match (low, high) {
+            (true, true) => return,
+            (false, true) => "low end",
+            (true, false) => "high end",
+            (false, false) => "both ends",
+        };
+        self.write_canaries();
+        let name = match &self.name {
+            Some(name) => format!(" '{}'", name),
+            None => String::new(),
+        };
+        panic!(
+            "stack canary clobbered at the {} of the stack of task {:?}{}",
+            end, id, name
+        );
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        // the thread never ran, so its argument is still on the stack.
+        if let Some(arg) = self.arg.take() {
+            unsafe { (arg.drop)(arg.ptr) }
+        }
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
+    }
+}
+
+impl Drop for Runtime {
+    // The threads and their stacks are freed with the Runtime. Closures of threads which never
+    // started are dropped, and so are the ones Handles spawn from now on, but a thread dropped
+    // while it's suspended in the middle of its closure never gets to drop what lives on its
+    // stack.
+    fn drop(&mut self) {
+        let injected = {
+            let mut injected = self.remote.injected.lock().unwrap();
+            self.remote.closed.store(true, Ordering::Relaxed);
+            mem::take(&mut *injected)
+        };
+        // outside the lock, since dropping a closure may spawn through a Handle.
+        drop(injected);
+        // only if it's still the one initialized, since another runtime may have been since.
+        // Compare the Remote instead of the address, since the runtime may have been moved.
+        if self.initialized.get() && REMOTE.with(Cell::get) == Arc::as_ptr(&self.remote) {
+            RUNTIME.with(|rt| rt.set(ptr::null_mut()));
+            REMOTE.with(|remote| remote.set(ptr::null()));
+        }
+    }
+}
+
+impl Handle {
+    /// spawn a closure onto the runtime, like Runtime::spawn. It's queued once the runtime
+    /// schedules again, which wakes it up if it's parked, and started as soon as a thread is
+    /// available. If the runtime is dropped before starting it, or already was, or if as many
+    /// tasks as RuntimeBuilder::max_tasks allows are alive when it's queued, join returns
+    /// JoinError::Cancelled.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let id = TaskId::next();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let packet = Arc::new(Mutex::new(Packet {
+            result: None,
+            done: false,
+            waiter: None,
+            stack_used: None,
+        }));
+        let completion = Completion(packet.clone());
+        let mut injected = self.remote.injected.lock().unwrap();
+        // dropping the task of a dropped runtime lets its JoinHandle know right away.
+        if !self.remote.closed.load(Ordering::Relaxed) {
+            injected.push(Injected {
+                task_id: id,
+                cancelled: cancelled.clone(),
+                task: Box::new(move || run_task(f, completion)),
+            });
+        }
+        drop(injected);
+        self.remote.unpark();
+        JoinHandle {
+            id,
+            packet,
+            cancelled,
+        }
+    }
+
+    // unpark the task id like the unpark function, from any OS thread. It's unparked the next
+    // time the runtime schedules.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn unpark(&self, id: TaskId) {
+        self.remote.woken.lock().unwrap().push(id);
+        self.remote.unpark();
+    }
+
+    /// shutdown shuts the runtime down like Runtime::shutdown, from any OS thread. It's taken up
+    /// the next time the runtime schedules, which wakes it up if it's parked.
+    pub fn shutdown(&self) {
+        self.remote.stopping.store(true, Ordering::Release);
+        self.remote.unpark();
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        self.remote.handles.fetch_add(1, Ordering::Relaxed);
+        Handle {
+            remote: self.remote.clone(),
+        }
+    }
+}
+
+impl Drop for Handle {
+    // the last Handle lets a run with nothing left to do return.
+    fn drop(&mut self) {
+        self.remote.handles.fetch_sub(1, Ordering::Release);
+        self.remote.unpark();
+    }
+}
+
+impl Remote {
+    // unpark the OS thread running the runtime, if it's running.
+    fn unpark(&self) {
+        if let Some(thread) = &*self.thread.lock().unwrap() {
+            thread.unpark();
+        }
+    }
+
+    // wait parks the OS thread running the runtime, which has nothing left to do, until a Handle
+    // spawns or unparks a task or shuts it down, and returns true, or until there are no Handles
+    // left, and returns false.
+    fn wait(&self) -> bool {
+        loop {
+            if self.has_news() {
+                return true;
+            }
+            if self.handles.load(Ordering::Acquire) == 0 {
+                // the last Handle may have spawned or unparked something right before it went.
+                return self.has_news();
+            }
+            thread::park();
+        }
+    }
+
+    // whether a Handle has given the runtime something to do.
+    fn has_news(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+            || !self.injected.lock().unwrap().is_empty()
+            || !self.woken.lock().unwrap().is_empty()
+    }
+}
+
+impl Runtime {
+    /// Initialize with a base thread. It's a shortcut for RuntimeBuilder::default().build().
+    pub fn new() -> Self {
+        RuntimeBuilder::default().build()
+    }
+
+    /// configure a Runtime with a RuntimeBuilder.
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder::default()
+    }
+
+    /// This is cheating a bit, but we need a pointer to our Runtime
+    /// stored so we can call yield on it even if we don't have a
+    /// reference to it.
+    ///
+    /// The pointer is kept per OS thread, so every OS thread can have a runtime of its own, and
+    /// the functions like spawn and yield_thread use the one of the calling OS thread. The
+    /// runtime mustn't move between init and run.
+    ///
+    /// ```
+    /// use std::thread;
+    ///
+    /// use green_threads::{spawn, yield_thread, Runtime};
+    ///
+    /// fn count(n: usize) -> usize {
+    ///     let mut runtime = Runtime::new();
+    ///     runtime.init();
+    ///     let handles: Vec<_> = (0..n)
+    ///         .map(|i| {
+    ///             spawn(move || {
+    ///                 yield_thread();
+    ///                 i
+    ///             })
+    ///         })
+    ///         .collect();
+    ///     runtime.run();
+    ///     handles.into_iter().map(|h| h.join().unwrap()).sum()
+    /// }
+    ///
+    /// // one runtime after another, and two at the same time on different OS threads.
+    /// assert_eq!(count(3), 3);
+    /// assert_eq!(count(4), 6);
+    /// let other = thread::spawn(|| count(10));
+    /// assert_eq!(count(5), 10);
+    /// assert_eq!(other.join().unwrap(), 45);
+    /// ```
+    pub fn init(&self) {
+        let r_ptr: *const Runtime = self;
+        RUNTIME.with(|rt| rt.set(r_ptr as *mut Runtime));
+        REMOTE.with(|remote| remote.set(Arc::as_ptr(&self.remote)));
+        self.initialized.set(true);
+        PANIC_HOOK.call_once(install_panic_hook);
+    }
+
+    /// a snapshot of what the scheduler has done so far, e.g. to print after run.
+    ///
+    /// ```
+    /// use green_threads::Runtime;
+    ///
+    /// let mut runtime = Runtime::new();
+    /// runtime.init();
+    /// for i in 0..1000 {
+    ///     runtime.spawn(move || i * 2);
+    ///     runtime.run();
+    /// }
+    /// let stats = runtime.stats();
+    /// assert_eq!(stats.completed, 1000);
+    /// // every task ran on the stack of the first one.
+    /// assert_eq!(stats.stacks, 1);
+    /// ```
+    pub fn stats(&self) -> RuntimeStats {
+        self.stats
+    }
+
+    /// a Handle to spawn tasks onto the runtime from other OS threads.
+    pub fn handle(&self) -> Handle {
+        self.remote.handles.fetch_add(1, Ordering::Relaxed);
+        Handle {
+            remote: self.remote.clone(),
+        }
+    }
+
+    /// the number of tasks which have been spawned but haven't started yet, whether they have
+    /// a thread already, are waiting for one to be available, or are delayed by spawn_after.
+    /// The tasks spawned by Handles count from the moment they're spawned. They're counted
+    /// without waiting for a Handle which is spawning at the same time, by falling back to how
+    /// many there were the last time, so the count is only approximate while Handles spawn. It's
+    /// cheap enough to poll, e.g. to monitor the backlog next to stats.
+    ///
+    /// ```
+    /// use green_threads::Runtime;
+    ///
+    /// let mut runtime = Runtime::new();
+    /// runtime.init();
+    /// for i in 0..10 {
+    ///     runtime.spawn(move || i);
+    /// }
+    /// assert_eq!(runtime.pending_tasks(), 10);
+    /// runtime.run();
+    /// assert_eq!(runtime.pending_tasks(), 0);
+    /// ```
+    pub fn pending_tasks(&self) -> usize {
+        let unstarted = self
+            .threads
+            .iter()
+            .filter(|t| t.task.is_some() || t.arg.is_some())
+            .count();
+        let queued: usize = self.queues.iter().map(VecDeque::len).sum();
+        let injected = match self.remote.injected.try_lock() {
+            Ok(injected) => {
+                self.last_injected.set(injected.len());
+                injected.len()
+            }
+            Err(_) => self.last_injected.get(),
+        };
+        unstarted + queued + self.delayed.len() + injected
+    }
+
+    /// Start the runtime, and return when all spawned threads have finished, or once they're
+    /// shut down if shutdown is called. It returns how many tasks were dropped before they
+    /// started in the meantime, by a shutdown or JoinHandle::abort, which RuntimeStats::abandoned
+    /// adds up over every run.
+    ///
+    /// Threads run one at a time on the calling OS thread, in an order which only depends on
+    /// what they do: the same spawns, yields and joins always give the same interleaving, so
+    /// tests can assert on it. Only sleep, spawn_after and spawn_blocking bring in timing, since
+    /// when they're done depends on the clock or on other OS threads.
+    pub fn run(&mut self) -> u64 {
+        let abandoned = self.stats.abandoned;
+        *self.remote.thread.lock().unwrap() = Some(thread::current());
+        // the base thread has nothing to do but wait for the others, so it's idle as soon as
+        // they are, and once more after Handles spawned anything.
+        loop {
+            let mut retry = false;
+            while self.t_wait(retry) {
+                retry = true;
+            }
+            if self.is_stopping() || !self.remote.wait() {
+                break;
+            }
+        }
+        *self.remote.thread.lock().unwrap() = None;
+        self.remote.stopping.store(false, Ordering::Relaxed);
+        self.stopping.set(false);
+        self.stats.abandoned - abandoned
+    }
+
+    /// shutdown stops the runtime: the tasks which haven't started yet are dropped, and the
+    /// started ones are resumed once more, with is_shutting_down telling them to wrap up, and
+    /// aborted at their next yield_thread, where they unwind. run returns once they're all
+    /// gone, even though they didn't finish, with the number of tasks dropped before they
+    /// started. The spawn functions which return a Result fail with Error::ShuttingDown until
+    /// then, the spawn function panics, and the tasks spawned by the methods of Runtime are
+    /// dropped. The runtime can be run again once run returns. Use the shutdown function from
+    /// inside a task, or Handle::shutdown from another OS thread.
+    pub fn shutdown(&self) {
+        self.stopping.set(true);
+    }
+
+    // whether the runtime is shutting down, taking over a shutdown requested by a Handle.
+    fn is_stopping(&self) -> bool {
+        if self.remote.stopping.swap(false, Ordering::Acquire) {
+            self.stopping.set(true);
+        }
+        self.stopping.get()
+    }
+
+    fn t_return(&mut self) {
+        if self.current != 0 {
+            self.threads[self.current].check_canaries();
+            // drop the task locals while the thread is still running, since their destructors
+            // may yield.
+            drop(mem::take(&mut self.threads[self.current].locals));
+            self.stats.completed += 1;
+            self.threads[self.current].state = State::Available;
+            if let Some(id) = self.threads[self.current].task_id.take() {
+                self.unparked.remove(&id);
+            }
+            self.threads[self.current].cancelled = None;
+            self.idle_yields = 0;
+            if !self.t_yield() {
+                // nothing else is ready to run, not even the base thread. Resume the base thread
+                // anyway instead of returning to the end of the finished thread's stack, so that
+                // run's loop gets to see there's nothing left and terminate.
+                self.threads[0].state = State::Running;
+                let old_pos = self.current;
+                self.current = 0;
+                unsafe {
+                    switch(&mut self.threads[old_pos].ctx, &self.threads[0].ctx);
+                }
+            }
+        }
+    }
+
+    // Never inline t_yield into a caller's loop: other threads change the runtime while we're
+    // switched out, so the fields must be read again every time we come back from switch.
+    #[inline(never)]
+    fn t_yield(&mut self) -> bool {
+        self.threads[self.current].check_canaries();
+        if self.is_stopping() {
+            self.abort_all();
+        }
+        self.remove_cancelled();
+        // whichever thread runs next starts a new slice.
+        self.budget = BUDGET;
+        if let Some(timer) = &self.timer {
+            timer.switched();
+        }
+
+        let back = self.give_back.take();
+        let pos = loop {
+            self.wake_sleepers();
+            self.take_injected();
+            self.start_queued();
+            if let Some(pos) = self.next_ready() {
+                break pos;
+            }
+            // every thread left is sleeping or delayed, so park until the first of them is due,
+            // or until a Handle unparks us to spawn a task or shut the runtime down.
+            match self.next_deadline() {
+                Some(deadline) => {
+                    thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => return false,
+            }
+            if self.is_stopping() {
+                self.abort_all();
+                self.remove_cancelled();
+            }
+        };
+
+        if pos == self.current {
+            // we were sleeping and woke up with no other thread ready to run.
+            self.threads[pos].state = State::Running;
+            self.threads[pos].age = 0;
+            return false;
+        }
+
+        if self.threads[self.current].state == State::Running {
+            self.threads[self.current].state = State::Ready;
+        }
+
+        if self.threads[pos].task.is_some() {
+            // the task is about to start, so its thread needs a stack now.
+            let stack_size = self.threads[pos].stack_size;
+            let stack = self.take_stack(pos, stack_size);
+            let thread = &mut self.threads[pos];
+            let stack = thread.stack.insert(stack);
+            if self.measure_stack_usage {
+                stack.fill(STACK_PATTERN);
+            }
+            let top = stack.len() - CANARY_GAP;
+            thread.ctx.prime(stack, call_task as *const (), top);
+            if CANARIES {
+                thread.write_canaries();
+            }
+        }
+
+        self.threads[pos].state = State::Running;
+        self.threads[pos].age = 0;
+        if back.is_some() {
+            self.next = back;
+            self.cursor = Some(pos);
+        }
+        let old_pos = self.current;
+        self.current = pos;
+        self.stats.yields += 1;
+        if pos != 0 {
+            self.task_switches += 1;
+        }
+
+        unsafe {
+            switch(&mut self.threads[old_pos].ctx, &self.threads[pos].ctx);
+        }
+        // Prevents compiler from optimizing our code away on Windows.
+        !self.threads.is_empty()
+    }
+
+    // yield_switched yields like t_yield, and tells whether another task ran before the caller
+    // got back. The base thread doesn't count, since it only goes round run's loop, so a task
+    // which is the only one ready gets false even though it switched to the base thread and
+    // back. That the caller is resumed counts as one switch to a task, unless it's the base
+    // thread.
+    fn yield_switched(&mut self) -> bool {
+        let caller = self.current;
+        let before = self.task_switches;
+        if !self.t_yield() {
+            return false;
+        }
+        let resumed = if caller == 0 { 0 } else { 1 };
+        self.task_switches - before > resumed
+    }
+
+    // t_wait yields from a loop waiting for something another thread has to do, where retry
+    // tells whether the caller did nothing since it last yielded but find out it has to wait
+    // again. Once every ready thread has done that in a row, nothing can happen on the runtime
+    // until another OS thread or a timer steps in, so rather than going round in circles, the
+    // OS thread parks until it's unparked by whatever the threads are waiting for, or the
+    // earliest deadline is due.
+    #[inline(never)]
+    fn t_wait(&mut self, retry: bool) -> bool {
+        self.idle_yields = if retry { self.idle_yields + 1 } else { 1 };
+        let ready = self
+            .threads
+            .iter()
+            .filter(|t| matches!(t.state, State::Ready | State::Running))
+            .count();
+        if ready > 1 && self.idle_yields >= ready {
+            let available = self.threads.iter().any(|t| t.state == State::Available);
+            if self.queued_level().is_some() && !available {
+                // the threads may well be waiting for a task which is still queued, like a child
+                // they join, and none of them is going to finish to make room for it. Add a
+                // thread for it rather than park forever.
+                let id = self.threads.len();
+                self.threads.push(Thread::new(id));
+            } else {
+                match self.next_deadline() {
+                    Some(deadline) => {
+                        thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    None => thread::park(),
+                }
+            }
+            self.idle_yields = 0;
+        }
+        self.t_yield()
+    }
+
+    // drop the queued and delayed tasks and the threads which haven't started, and abort the
+    // current thread, which has got to a yield. The other threads are only woken up: they get
+    // to run until they yield as well, and are aborted then.
+    fn abort_all(&mut self) {
+        for queue in self.queues.iter_mut() {
+            self.stats.abandoned += queue.len() as u64;
+            queue.clear();
+        }
+        self.stats.abandoned += self.delayed.len() as u64;
+        self.delayed.clear();
+        self.timers.clear();
+        self.unparked.clear();
+        for thread in self.threads.iter_mut() {
+            if matches!(thread.state, State::Sleeping | State::Parked) {
+                thread.state = State::Ready;
+            }
+            let started = thread.task.is_none() && thread.arg.is_none();
+            if started && thread.id != self.current {
+                continue;
+            }
+            if let Some(cancelled) = &thread.cancelled {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // the ready thread of the highest priority, the first one after the current one in round
+    // robin order among them, which is the current one itself only if no other thread is ready.
+    // The order goes on after the cursor instead if yield_thread_front set it. The current
+    // thread doesn't compete while it's running, so yielding always lets another ready thread
+    // run, whatever its priority.
+    //
+    // The lifo thread goes first if it has the same priority, so a child spawned right before
+    // its parent yields runs while what it shares with its parent is still in the cache. Doing
+    // it more than MAX_LIFO_RUNS times in a row would let tasks spawning each other starve the
+    // other threads, so the round robin order takes over then.
+    fn next_ready(&mut self) -> Option<usize> {
+        if let Some(pos) = self.next.take() {
+            if pos != self.current && self.threads[pos].state == State::Ready {
+                return Some(pos);
+            }
+        }
+        let len = self.threads.len();
+        let from = self.cursor.take().unwrap_or(self.current);
+        let next = (1..=len)
+            .map(|i| (from + i) % len)
+            .filter(|&pos| self.threads[pos].state == State::Ready)
+            .min_by_key(|&pos| self.threads[pos].rank())?;
+        if let Some(pos) = self.aged_out(self.threads[next].priority) {
+            return Some(pos);
+        }
+        if let Some(pos) = self.lifo.take() {
+            let thread = &self.threads[pos];
+            if self.lifo_runs < MAX_LIFO_RUNS
+                && pos != self.current
+                && thread.state == State::Ready
+                && thread.rank() == self.threads[next].rank()
+            {
+                self.lifo_runs += 1;
+                return Some(pos);
+            }
+        }
+        self.lifo_runs = 0;
+        Some(next)
+    }
+
+    // make the parked thread of the task id ready, or remember to let its next park return.
+    fn unpark(&mut self, id: TaskId) -> Result<(), Error> {
+        if let Some(pos) = self.threads.iter().position(|t| t.task_id == Some(id)) {
+            if self.threads[pos].state == State::Parked {
+                self.threads[pos].state = State::Ready;
+                self.lifo = Some(pos);
+            } else {
+                self.unparked.insert(id);
+            }
+            return Ok(());
+        }
+        let queued = self.queues.iter().flatten().any(|q| q.task_id == id)
+            || self.delayed.iter().any(|Reverse(d)| d.queued.task_id == id);
+        if !queued {
+            return Err(Error::NoSuchTask(id));
+        }
+        self.unparked.insert(id);
+        Ok(())
+    }
+
+    // age the ready threads of a lower priority than the one about to run, and return the
+    // oldest of them to run instead if it's been passed over as many times as aging allows.
+    fn aged_out(&mut self, priority: Priority) -> Option<usize> {
+        let rounds = self.aging?;
+        let mut oldest = None;
+        for thread in &mut self.threads {
+            if thread.state != State::Ready || thread.priority >= priority {
+                continue;
+            }
+            thread.age += 1;
+            if thread.age >= rounds && oldest.is_none_or(|(_, age)| thread.age > age) {
+                oldest = Some((thread.id, thread.age));
+            }
+        }
+        oldest.map(|(pos, _)| pos)
+    }
+
+    // make the sleeping threads whose deadline has passed ready again, and queue the delayed
+    // tasks whose deadline has passed.
+    fn wake_sleepers(&mut self) {
+        let now = Instant::now();
+        while let Some(&Reverse((deadline, pos))) = self.timers.peek() {
+            if deadline > now {
+                break;
+            }
+            self.timers.pop();
+            self.threads[pos].state = State::Ready;
+            self.lifo = Some(pos);
+        }
+        loop {
+            let Reverse(delayed) = match self.delayed.peek_mut() {
+                Some(due) if due.0.deadline <= now => PeekMut::pop(due),
+                _ => break,
+            };
+            self.push_queued(delayed.queued);
+        }
+    }
+
+    // make the thread running the task id run next, starting the task first if it's queued. It
+    // fails without changing what runs next if the task isn't ready to run, e.g. because it has
+    // finished.
+    fn prefer(&mut self, id: TaskId) -> Result<(), Error> {
+        for level in 0..self.queues.len() {
+            let i = self.queues[level].iter().position(|q| q.task_id == id);
+            if let Some(queued) = i.and_then(|i| self.queues[level].remove(i)) {
+                if !self.start(queued) {
+                    return Err(Error::NoAvailableThread);
+                }
+            }
+        }
+        let pos = self
+            .threads
+            .iter()
+            .position(|t| t.task_id == Some(id))
+            .ok_or(Error::NoSuchTask(id))?;
+        if pos == self.current || self.threads[pos].state != State::Ready {
+            return Err(Error::TaskNotReady(id));
+        }
+        self.next = Some(pos);
+        Ok(())
+    }
+
+    // the earliest deadline of the sleeping threads and the delayed tasks. The delayed tasks
+    // aborted in the meantime are dropped first, so nobody waits for them to be due. It's only
+    // called when there's nothing to run, so going through all of them doesn't hold up anything.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        let delayed = self.delayed.len();
+        self.delayed
+            .retain(|Reverse(delayed)| !delayed.queued.cancelled.load(Ordering::Relaxed));
+        self.stats.abandoned += (delayed - self.delayed.len()) as u64;
+        let sleeping = self.timers.peek().map(|Reverse((deadline, _))| *deadline);
+        let delayed = self.delayed.peek().map(|Reverse(delayed)| delayed.deadline);
+        sleeping.into_iter().chain(delayed).min()
+    }
+
+    // remove the aborted threads which haven't started yet, so they never run. Dropping the
+    // closure lets their JoinHandle know it's been cancelled.
+    fn remove_cancelled(&mut self) {
+        for thread in self.threads.iter_mut() {
+            // an aborted thread which is parked or sleeping gets to unwind, rather than waiting
+            // for unpark or its deadline.
+            if thread.state == State::Parked && thread.is_cancelled() {
+                thread.state = State::Ready;
+            }
+            if thread.state == State::Sleeping && thread.is_cancelled() {
+                let pos = thread.id;
+                self.timers.retain(|&Reverse((_, timer))| timer != pos);
+                thread.state = State::Ready;
+            }
+            let started = thread.task.is_none() && thread.arg.is_none();
+            if thread.state == State::Ready && !started && thread.is_cancelled() {
+                thread.task = None;
+                if let Some(arg) = thread.arg.take() {
+                    unsafe { (arg.drop)(arg.ptr) }
+                }
+                if let Some(id) = thread.task_id.take() {
+                    self.unparked.remove(&id);
+                }
+                thread.cancelled = None;
+                thread.state = State::Available;
+                self.stats.abandoned += 1;
+            }
+        }
+    }
+
+    /// spawn a closure to be executed by runtime. The returned JoinHandle can be used to wait
+    /// for its result, or dropped to detach the thread. If all the threads are taken, the closure
+    /// is queued and starts once one of them finishes.
+    pub fn spawn<F, T>(&mut self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let stack_size = self.default_stack_size;
+        unsafe { self.spawn_unchecked(None, stack_size, f) }
+    }
+
+    /// spawn_future spawns a thread polling fut until it's ready, like the spawn_future
+    /// function.
+    pub fn spawn_future<F>(&mut self, fut: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn(move || future::run(fut))
+    }
+
+    /// spawn a closure on a thread with a stack of stack_size bytes instead of the default one.
+    /// stack_size is rounded up to 16 bytes, and it fails with Error::StackTooSmall if it's less
+    /// than 4 KiB. Whatever the size, the thread starts with its stack aligned the way the ABI
+    /// requires, so code relying on it, like SSE moves of aligned locals, works.
+    ///
+    /// ```
+    /// use green_threads::Runtime;
+    ///
+    /// #[repr(align(16))]
+    /// struct Aligned([f64; 2]);
+    ///
+    /// let mut runtime = Runtime::new();
+    /// runtime.init();
+    /// let handles: Vec<_> = (0..4)
+    ///     .map(|i| {
+    ///         runtime
+    ///             .spawn_with_stack(64 * 1024 + i * 8, move || {
+    ///                 let local = Aligned([i as f64 / 3.0, 1e-300]);
+    ///                 assert_eq!(&local as *const Aligned as usize % 16, 0);
+    ///                 // an aligned SSE load faults if the stack isn't aligned.
+    ///                 #[cfg(target_arch = "x86_64")]
+    ///                 unsafe {
+    ///                     use std::arch::x86_64::{_mm_cvtsd_f64, _mm_load_pd};
+    ///                     assert_eq!(_mm_cvtsd_f64(_mm_load_pd(local.0.as_ptr())), local.0[0]);
+    ///                 }
+    ///                 format!("{:.3} {:e}", local.0[0], local.0[1])
+    ///             })
+    ///             .unwrap()
+    ///     })
+    ///     .collect();
+    /// runtime.run();
+    /// let formatted: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    /// assert_eq!(formatted[3], "1.000 1e-300");
+    /// ```
+    pub fn spawn_with_stack<F, T>(
+        &mut self,
+        stack_size: usize,
+        f: F,
+    ) -> Result<JoinHandle<T>, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if stack_size < MIN_STACK_SIZE {
+            return Err(Error::StackTooSmall(stack_size));
+        }
+        Ok(unsafe { self.spawn_unchecked(None, (stack_size + 15) & !15, f) })
+    }
+
+    /// spawn a closure on a thread with a name, which can be read from inside the thread with
+    /// current_name. It's handy to tell threads apart in debugging output.
+    pub fn spawn_named<F, T>(&mut self, name: &str, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let stack_size = self.default_stack_size;
+        unsafe { self.spawn_unchecked(Some(name.to_string()), stack_size, f) }
+    }
+
+    /// block_on initializes the runtime, runs f as the root thread and returns its result once
+    /// it finishes, resuming its panic if it panicked. Other threads keep running while the root
+    /// thread is waiting, but block_on returns as soon as the root thread finishes, and the
+    /// threads that are still left run on the next run, block_on or yield on the base thread.
+    pub fn block_on<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send,
+        T: Send,
+    {
+        self.init();
+        let stack_size = self.default_stack_size;
+        // f may borrow from the caller, which is fine since we wait for it to finish.
+        let handle = unsafe { self.spawn_unchecked(None, stack_size, f) };
+        match handle.join() {
+            Ok(result) => result,
+            Err(JoinError::Panicked(payload)) => panic::resume_unwind(payload),
+            Err(err) => panic!("root thread failed: {}", err),
+        }
+    }
+
+    /// scope creates a Scope to spawn threads borrowing anything that outlives the call, like
+    /// local variables of the caller, and waits for all of them to finish before returning,
+    /// yielding to other threads in the meantime the way JoinHandle::join does. If any of them
+    /// panicked, the first panic is resumed once they all finish. Threads aborted by a shutdown
+    /// aren't panics: the ones which haven't started are dropped without running, and scope
+    /// returns once the others unwind. The runtime must be initialized.
+    ///
+    /// ```
+    /// use green_threads::{yield_thread, Runtime};
+    ///
+    /// let mut runtime = Runtime::new();
+    /// runtime.init();
+    /// let numbers = vec![1, 2, 3, 4, 5, 6];
+    /// let (mut left, mut right) = (0, 0);
+    /// runtime.scope(|s| {
+    ///     s.spawn(|| {
+    ///         yield_thread();
+    ///         left = numbers[..3].iter().sum();
+    ///     });
+    ///     s.spawn(|| right = numbers[3..].iter().sum());
+    /// });
+    /// assert_eq!((left, right), (6, 15));
+    /// assert_eq!(numbers.len(), 6);
+    /// ```
+    pub fn scope<'env, F, T>(&'env mut self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        assert!(
+            self.initialized.get(),
+            "the runtime must be initialized to spawn scoped threads."
+        );
+        let scope = Scope {
+            data: Arc::new(ScopeData {
+                running: AtomicUsize::new(0),
+                panics: Mutex::new(Vec::new()),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        // wait like a join does, so the OS thread parks if the scoped threads are all waiting.
+        wait(|| (scope.data.running.load(Ordering::Acquire) == 0).then_some(()));
+
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        if let Some(payload) = scope.data.panics.lock().unwrap().drain(..).next() {
+            panic::resume_unwind(payload);
+        }
+        result
+    }
+
+    /// spawn_many spawns every closure of tasks at once, like calling spawn for each of them. The
+    /// queue is grown once for the whole batch, and the threads are handed out once all of them
+    /// are queued. The JoinHandles are returned in the order of tasks.
+    ///
+    /// ```
+    /// use green_threads::{join_all, yield_thread, Runtime};
+    ///
+    /// let mut runtime = Runtime::new();
+    /// runtime.init();
+    /// let handles = runtime.spawn_many((0..50).map(|i| {
+    ///     move || {
+    ///         yield_thread();
+    ///         i * 2
+    ///     }
+    /// }));
+    /// runtime.run();
+    /// let results: Vec<_> = join_all(handles).into_iter().map(Result::unwrap).collect();
+    /// assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<_>>());
+    /// ```
+    pub fn spawn_many<I, F, T>(&mut self, tasks: I) -> Vec<JoinHandle<T>>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let tasks = tasks.into_iter();
+        self.queues[Priority::Normal as usize].reserve(tasks.size_hint().0);
+        let stack_size = self.default_stack_size;
+        let handles = tasks
+            .map(|f| {
+                let (queued, handle) = unsafe { self.new_task(None, stack_size, f) };
+                self.push_queued(queued);
+                handle
+            })
+            .collect();
+        self.start_queued();
+        handles
+    }
+
+    // spawn_unchecked doesn't require Send since every thread runs on the OS thread of the
+    // runtime. It doesn't require 'static either, so the caller must make sure the closure runs
+    // or is dropped, and its result is taken, before anything it borrows goes away.
+    unsafe fn spawn_unchecked<'a, F, T>(
+        &mut self,
+        name: Option<String>,
+        stack_size: usize,
+        f: F,
+    ) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'a,
+        T: 'a,
+    {
+        let (queued, handle) = self.new_task(name, stack_size, f);
+        self.push_queued(queued);
+        self.start_queued();
+        handle
+    }
+
+    /// spawn a closure with a priority instead of Priority::Normal. Whenever the runtime picks
+    /// the next thread to run or the next queued task to start, it takes one of the highest
+    /// priority. A yielding thread always lets another one run, but a thread of a lower priority
+    /// only runs when no other thread of a higher one is ready, so it starves as long as they
+    /// keep yielding, unless the runtime is built with RuntimeBuilder::aging. The base thread
+    /// counts as a thread of Priority::Normal.
+    pub fn spawn_with_priority<F, T>(&mut self, priority: Priority, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let stack_size = self.default_stack_size;
+        let (mut queued, handle) = unsafe { self.new_task(None, stack_size, f) };
+        queued.priority = priority;
+        self.push_queued(queued);
+        self.start_queued();
+        handle
+    }
+
+    /// spawn a closure with a deadline, for earliest deadline first scheduling. Among the ready
+    /// threads of the same priority, the one with the earliest deadline runs first, and threads
+    /// without one only run when no thread with a deadline is ready. A yielding thread keeps its
+    /// deadline, so it runs again next if it's still the most urgent. Tasks waiting for a thread
+    /// start in the order of their deadlines as well. A deadline which has passed isn't treated
+    /// any differently, it just stays the most urgent. Runtimes where no task has a deadline
+    /// schedule in round robin order as before.
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use green_threads::{yield_thread, Runtime};
+    ///
+    /// let mut runtime = Runtime::builder().max_threads(5).build();
+    /// runtime.init();
+    /// let order = Arc::new(Mutex::new(Vec::new()));
+    /// let start = Instant::now();
+    /// for i in [3, 1, 4, 0, 2] {
+    ///     let order = order.clone();
+    ///     let deadline = start + Duration::from_secs(i);
+    ///     runtime.spawn_with_deadline(deadline, move || {
+    ///         yield_thread();
+    ///         order.lock().unwrap().push(i);
+    ///     });
+    /// }
+    /// runtime.run();
+    /// assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3, 4]);
+    /// ```
+    pub fn spawn_with_deadline<F, T>(&mut self, deadline: Instant, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let stack_size = self.default_stack_size;
+        let (mut queued, handle) = unsafe { self.new_task(None, stack_size, f) };
+        queued.deadline = Some(deadline);
+        self.push_queued(queued);
+        self.start_queued();
+        handle
+    }
+
+    /// spawn_after spawns a closure which starts once delay has passed. Until then it doesn't
+    /// take a thread, and aborting its JoinHandle before the deadline makes sure it never
+    /// starts, and that run doesn't wait for the deadline. Tasks which are due at the same time start in the order of their deadlines, or
+    /// of their spawns if the deadlines are equal.
+    pub fn spawn_after<F, T>(&mut self, delay: Duration, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let stack_size = self.default_stack_size;
+        let (queued, handle) = unsafe { self.new_task(None, stack_size, f) };
+        self.delayed.push(Reverse(Delayed {
+            deadline: Instant::now() + delay,
+            seq: self.stats.spawned,
+            queued,
+        }));
+        handle
+    }
+
+    // new_task creates a task to be queued and its JoinHandle, with the same requirements as
+    // spawn_unchecked.
+    unsafe fn new_task<'a, F, T>(
+        &mut self,
+        name: Option<String>,
+        stack_size: usize,
+        f: F,
+    ) -> (Queued, JoinHandle<T>)
+    where
+        F: FnOnce() -> T + 'a,
+        T: 'a,
+    {
+        if let Err(err) = self.check_capacity() {
+            panic!("failed to spawn: {}", err);
+        }
+        let id = TaskId::next();
+        self.stats.spawned += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let packet = Arc::new(Mutex::new(Packet {
+            result: None,
+            done: false,
+            waiter: None,
+            stack_used: None,
+        }));
+        let completion = Completion(packet.clone());
+        let task: Box<dyn FnOnce() + 'a> = Box::new(move || run_task(f, completion));
+        let queued = Queued {
+            task_id: id,
+            priority: Priority::Normal,
+            deadline: None,
+            name,
+            stack_size,
+            cancelled: cancelled.clone(),
+            task: mem::transmute::<Box<dyn FnOnce() + 'a>, Box<dyn FnOnce() + 'static>>(task),
+        };
+        let handle = JoinHandle {
+            id,
+            packet,
+            cancelled,
+        };
+        (queued, handle)
+    }
+
+    // queue the tasks spawned by Handles since the last time, and unpark the tasks they unparked.
+    fn take_injected(&mut self) {
+        let woken = mem::take(&mut *self.remote.woken.lock().unwrap());
+        for id in woken {
+            // the task may have finished or been aborted in the meantime.
+            let _ = self.unpark(id);
+        }
+        let injected = mem::take(&mut *self.remote.injected.lock().unwrap());
+        for injected in injected {
+            // there is nobody to fail the spawn to, so a task beyond max_tasks is dropped, which
+            // cancels its JoinHandle.
+            let full = self.check_capacity().is_err();
+            self.stats.spawned += 1;
+            if full {
+                self.stats.abandoned += 1;
+                continue;
+            }
+            self.push_queued(Queued {
+                task_id: injected.task_id,
+                priority: Priority::Normal,
+                deadline: None,
+                name: None,
+                stack_size: self.default_stack_size,
+                cancelled: injected.cancelled,
+                task: injected.task,
+            });
+        }
+    }
+
+    // fail if the runtime is shutting down, for the spawns which can report it rather than
+    // dropping the task.
+    fn check_running(&self) -> Result<(), Error> {
+        if self.is_stopping() {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    // fail if as many tasks are alive as max_tasks allows.
+    fn check_capacity(&self) -> Result<(), Error> {
+        let alive = self.stats.spawned - self.stats.completed - self.stats.abandoned;
+        match self.max_tasks {
+            Some(max) if alive >= max as u64 => Err(Error::TooManyTasks(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // queue a task at the back of the queue of its priority, or if it has a deadline, in front
+    // of the first task with a later deadline or none at all.
+    fn push_queued(&mut self, queued: Queued) {
+        let queue = &mut self.queues[queued.priority as usize];
+        match queued.deadline {
+            Some(deadline) => {
+                let i = queue
+                    .iter()
+                    .position(|q| q.deadline.is_none_or(|d| d > deadline))
+                    .unwrap_or(queue.len());
+                queue.insert(i, queued);
+            }
+            None => queue.push_back(queued),
+        }
+    }
+
+    // start the queued tasks on the available threads, the highest priority first and then in
+    // the order they were spawned.
+    fn start_queued(&mut self) {
+        while let Some(level) = self.queued_level() {
+            let queued = match self.queues[level].pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            // an aborted task is dropped instead of started, which lets its JoinHandle know.
+            if queued.cancelled.load(Ordering::Relaxed) {
+                self.unparked.remove(&queued.task_id);
+                self.stats.abandoned += 1;
+                continue;
+            }
+            if !self.start(queued) {
+                return;
+            }
+            // the queues of a lower priority than the one just served get older.
+            self.queue_ages[level] = 0;
+            for lower in 0..level {
+                if self.queues[lower].is_empty() {
+                    self.queue_ages[lower] = 0;
+                } else {
+                    self.queue_ages[lower] += 1;
+                }
+            }
+        }
+    }
+
+    // the queue to start a task from next: the oldest one which has aged out if aging is
+    // enabled, or else the one of the highest priority with a task in it.
+    fn queued_level(&self) -> Option<usize> {
+        let highest = (0..self.queues.len())
+            .rev()
+            .find(|&l| !self.queues[l].is_empty())?;
+        let aged_out = self.aging.and_then(|rounds| {
+            (0..highest)
+                .filter(|&l| !self.queues[l].is_empty() && self.queue_ages[l] >= rounds)
+                .max_by_key(|&l| self.queue_ages[l])
+        });
+        Some(aged_out.unwrap_or(highest))
+    }
+
+    // start a queued task on an available thread, or put it back at the front of its queue and
+    // return false if there is none.
+    fn start(&mut self, queued: Queued) -> bool {
+        let available = match self.available_thread() {
+            Ok(available) => available,
+            Err(_) => {
+                self.queues[queued.priority as usize].push_front(queued);
+                return false;
+            }
+        };
+        let pos = available.id;
+        available.task_id = Some(queued.task_id);
+        available.priority = queued.priority;
+        available.deadline = queued.deadline;
+        available.name = queued.name;
+        available.cancelled = Some(queued.cancelled);
+        available.task = Some(queued.task);
+        available.stack_size = queued.stack_size;
+        available.locals.clear();
+        available.age = 0;
+        // the stack is only given to the thread by t_yield, once the task is about to run.
+        available.state = State::Ready;
+        if self.current != 0 && self.threads[self.current].state == State::Running {
+            self.lifo = Some(pos);
+        }
+        true
+    }
+
+    /// spawn a function with its argument without boxing anything. The argument is written to
+    /// the top of the new thread's stack and moved out right before f is called, which saves an
+    /// allocation per spawn for workloads spawning lots of tiny threads. Unlike spawn, it fails
+    /// with Error::NoAvailableThread if all the threads are taken, since the argument has nowhere
+    /// to live until one is free, with Error::TooManyTasks if max_tasks are alive, and with
+    /// Error::ShuttingDown if the runtime is shutting down.
+    pub fn spawn_with_arg<T>(&mut self, f: fn(T), arg: T) -> Result<(), Error>
+    where
+        T: Send + 'static,
+    {
+        self.check_running()?;
+        self.check_capacity()?;
+        let pos = self.available_thread()?.id;
+        let stack_size = self.default_stack_size;
+        let stack = self.take_stack(pos, stack_size);
+        let available = &mut self.threads[pos];
+        available.task_id = Some(TaskId::next());
+        // there is no JoinHandle to abort it, but shutdown may.
+        available.cancelled = Some(Arc::new(AtomicBool::new(false)));
+        available.priority = Priority::Normal;
+        available.deadline = None;
+        available.name = None;
+        available.locals.clear();
+        available.age = 0;
+
+        let stack = available.stack.insert(stack);
+        let size = stack.len();
+        let s_ptr = stack.as_mut_ptr();
+        let stack_top = s_ptr as usize + size - CANARY_GAP;
+        // keep the argument at the top, below the gap of the high canary if any, aligned for T,
+        // and the trampoline below it.
+        let arg_ptr = (stack_top - mem::size_of::<T>()) & !(mem::align_of::<T>() - 1);
+        let top = (arg_ptr & !15) - s_ptr as usize;
+
+        unsafe {
+            ptr::write(arg_ptr as *mut T, arg);
+        }
+        available.arg = Some(StackArg {
+            ptr: arg_ptr as *mut u8,
+            f: f as usize,
+            drop: drop_arg::<T>,
+        });
+
+        available
+            .ctx
+            .prime(stack, call_arg_task::<T> as *const (), top);
+        if CANARIES {
+            available.write_canaries();
+        }
+        available.state = State::Ready;
+        self.stats.spawned += 1;
+        Ok(())
+    }
+
+    // find an available thread. The current thread is skipped even if it's available, since
+    // it's still running on its stack until it switches away in t_return.
+    fn available_thread(&mut self) -> Result<&mut Thread, Error> {
+        let current = self.current;
+        self.threads
+            .iter_mut()
+            .find(|t| t.state == State::Available && t.id != current)
+            .ok_or(Error::NoAvailableThread)
+    }
+
+    // take a stack of stack_size for the thread at pos, which the caller puts back into it. A
+    // thread keeps its stack between tasks, and if it has none yet or the size doesn't match, it
+    // takes a pooled one of the right size, or allocates one. The stack is primed again
+    // afterwards, so nothing left on it from its last task gets executed.
+    fn take_stack(&mut self, pos: usize, stack_size: usize) -> Stack {
+        let old = match self.threads[pos].stack.take() {
+            Some(stack) if stack.len() == stack_size => {
+                self.stats.stacks_reused += 1;
+                return stack;
+            }
+            old => old,
+        };
+        let stack = match self.stack_pool.iter().position(|s| s.len() == stack_size) {
+            Some(i) => {
+                self.stats.stacks_reused += 1;
+                self.stack_pool.swap_remove(i)
+            }
+            None => {
+                self.stats.stacks += 1;
+                Stack::new(stack_size, self.huge_page_stacks)
+            }
+        };
+        if let Some(old) = old {
+            if self.stack_pool.len() < MAX_POOLED_STACKS {
+                self.stack_pool.push(old);
+            } else {
+                self.stats.stacks_freed += 1;
+            }
+        }
+        stack
+    }
+}
+
+/// Error is returned by the runtime when a request can't be fulfilled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// the requested stack size is smaller than the minimum a thread needs to run.
+    StackTooSmall(usize),
+    /// the runtime isn't initialized on the calling OS thread.
+    NotInitialized,
+    /// all the threads of the runtime are taken by unfinished tasks.
+    NoAvailableThread,
+    /// no task with the id is queued or running, e.g. because it has already finished.
+    NoSuchTask(TaskId),
+    /// the task is the caller itself, or it's sleeping or waiting, so it can't be switched to.
+    TaskNotReady(TaskId),
+    /// as many tasks as RuntimeBuilder::max_tasks allows are alive.
+    TooManyTasks(usize),
+    /// the runtime is shutting down, so it doesn't take new tasks until run returns.
+    ShuttingDown,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::StackTooSmall(size) => write!(
+                f,
+                "stack size {} is less than the minimum of {} bytes",
+                size, MIN_STACK_SIZE
+            ),
+            Error::NotInitialized => write!(f, "runtime is not initialized on this thread"),
+            Error::NoAvailableThread => write!(f, "no available thread"),
+            Error::NoSuchTask(id) => write!(f, "no task with id {:?}", id),
+            Error::TaskNotReady(id) => write!(f, "task {:?} is not ready to run", id),
+            Error::TooManyTasks(max) => write!(f, "the limit of {} live tasks is reached", max),
+            Error::ShuttingDown => write!(f, "runtime is shutting down"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Scope is passed to the closure of Runtime::scope to spawn threads which can borrow from the
+/// caller of scope.
+pub struct Scope<'scope, 'env: 'scope> {
+    data: Arc<ScopeData>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// ScopeData counts the threads of a scope that are still running, and keeps their panics.
+struct ScopeData {
+    running: AtomicUsize,
+    panics: Mutex<Vec<Box<dyn Any + Send>>>,
+}
+
+// ScopeGuard is owned by the closure of a scoped thread and takes it off the count when it's
+// dropped, whether the closure ran or was dropped before it started, e.g. by a shutdown.
+struct ScopeGuard(Arc<ScopeData>);
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// spawn a closure which may borrow anything outliving the scope. A panic in it is caught,
+    /// and resumed by Runtime::scope once all the scope's threads finish.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.data.running.fetch_add(1, Ordering::Relaxed);
+        let guard = ScopeGuard(self.data.clone());
+        let task = move || {
+            // an abort, e.g. by a shutdown, isn't a panic of the closure.
+            match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Err(payload) if !payload.is::<Cancelled>() => {
+                    guard.0.panics.lock().unwrap().push(payload)
+                }
+                _ => {}
+            }
+            drop(guard);
+        };
+        // Runtime::scope doesn't return before the counter drops to zero, i.e. before every
+        // closure spawned here has run or been dropped, so whatever it borrows outlives it.
+        unsafe {
+            let rt = get_rt().expect("failed to spawn a scoped thread");
+            let stack_size = rt.default_stack_size;
+            rt.spawn_unchecked(None, stack_size, task);
+        }
+    }
+}
+
+/// call_task is the entry of every spawned thread. It takes the boxed closure out of the
+/// running thread and calls it. The box is consumed by the call, so it's freed as soon as
+/// the closure returns and we fall through to skip and guard.
+///
+/// Unwinding can't go past the entry of a thread, since there is no frame to unwind to beyond
+/// it, so a panic is caught here and the thread finishes as usual. The closures of spawn catch
+/// panics themselves to hand them to the JoinHandle, so this only drops the payload of the
+/// ones which have nowhere else to go.
+extern "C" fn call_task() {
+    unsafe {
+        let rt = &mut *rt_ptr();
+        let current = rt.current;
+        if let Some(task) = rt.threads[current].task.take() {
+            let _ = panic::catch_unwind(AssertUnwindSafe(task));
+        }
+    }
+}
+
+/// call_arg_task is the entry of threads spawned by spawn_with_arg. It moves the argument off
+/// the stack before anything else, so it's dropped as usual even if f panics. Like in
+/// call_task, a panic is caught so the thread finishes as usual.
+extern "C" fn call_arg_task<T>() {
+    unsafe {
+        let rt = &mut *rt_ptr();
+        let current = rt.current;
+        if let Some(arg) = rt.threads[current].arg.take() {
+            let f: fn(T) = mem::transmute(arg.f);
+            let arg = ptr::read(arg.ptr as *const T);
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| f(arg)));
+        }
+    }
+}
+
+// measure_stack returns how many bytes of its stack the running task has used so far, if the
+// runtime measures it, and adds them to the stats. Its stack was filled with STACK_PATTERN
+// before it started, so the lowest byte which doesn't hold it anymore is as deep as it went.
+fn measure_stack() -> Option<usize> {
+    let rt = unsafe { get_rt() }.ok()?;
+    if !rt.measure_stack_usage {
+        return None;
+    }
+    let stack = rt.threads[rt.current].stack.as_ref()?;
+    // the low canary isn't part of the pattern.
+    let skip = if CANARIES { CANARY_LEN } else { 0 };
+    let untouched = skip + stack[skip..].iter().position(|&b| b != STACK_PATTERN)?;
+    let used = stack.len() - untouched;
+    rt.stats.stacks_measured += 1;
+    rt.stats.total_stack_used += used as u64;
+    rt.stats.max_stack_used = rt.stats.max_stack_used.max(used as u64);
+    Some(used)
+}
+
+// install_panic_hook makes panics in spawned threads tell which one panicked by its name, as in
+// "green thread 'worker-3' panicked at src/main.rs:5:9", instead of naming the OS thread running
+// the runtime. Panics anywhere else are left to the previous hook.
+fn install_panic_hook() {
+    let prev = panic::take_hook();
+    panic::set_hook(Box::new(move |info| match current_name() {
+        Some(name) => {
+            let payload = info.payload();
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message,
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.as_str(),
+                    None => "Box<dyn Any>",
+                },
+            };
+            match info.location() {
+                Some(location) => {
+                    eprintln!(
+                        "green thread '{}' panicked at {}:\n{}",
+                        name, location, message
+                    )
+                }
+                None => eprintln!("green thread '{}' panicked:\n{}", name, message),
+            }
+        }
+        None => prev(info),
+    }));
+}
+
+unsafe fn drop_arg<T>(ptr: *mut u8) {
+    ptr::drop_in_place(ptr as *mut T);
+}
+
+pub(crate) fn guard() {
+    unsafe {
+        (*rt_ptr()).t_return();
+    }
+}
+
+// run_task runs the closure of a task and hands its result to the task's JoinHandle.
+fn run_task<F, T>(f: F, completion: Completion<T>)
+where
+    F: FnOnce() -> T,
+{
+    // yield_thread unwinds an aborted task with Cancelled, running its destructors.
+    let result = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Ok(result),
+        Err(payload) if payload.is::<Cancelled>() => Err(JoinError::Cancelled),
+        Err(payload) => Err(JoinError::Panicked(payload)),
+    };
+    if let Some(used) = measure_stack() {
+        completion.set_stack_used(used);
+    }
+    completion.complete(result);
+}
+
+/// spawn_local spawns a closure which isn't necessarily Send, e.g. one owning an Rc, on the
+/// runtime initialized on the calling OS thread. Threads never leave the OS thread of their
+/// runtime, so this is sound, while Runtime::spawn keeps requiring Send. It fails with
+/// Error::NotInitialized if no runtime is initialized on the calling OS thread, with
+/// Error::ShuttingDown if the runtime is shutting down, or with Error::TooManyTasks if the
+/// runtime has reached its RuntimeBuilder::max_tasks.
+pub fn spawn_local<F, T>(f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    unsafe {
+        let rt = get_rt()?;
+        rt.check_running()?;
+        rt.check_capacity()?;
+        let stack_size = rt.default_stack_size;
+        Ok(rt.spawn_unchecked(None, stack_size, f))
+    }
+}
+
+/// current returns the id of the task calling it, or None if it's called from the base thread or
+/// outside the runtime.
+pub fn current() -> Option<TaskId> {
+    let rt = unsafe { get_rt() }.ok()?;
+    rt.threads[rt.current].task_id
+}
+
+/// remaining_stack returns roughly how many bytes are left on the stack of the calling thread
+/// before it overflows, e.g. for a recursive algorithm to switch to an explicit stack before it
+/// runs out. It returns None if it's called from the base thread, whose stack belongs to the OS
+/// thread, or outside the runtime.
+///
+/// ```
+/// use green_threads::{remaining_stack, Runtime};
+///
+/// fn depth(n: usize) -> usize {
+///     let here = remaining_stack().unwrap();
+///     if n == 0 {
+///         return here;
+///     }
+///     let deeper = depth(n - 1);
+///     assert!(deeper < here);
+///     deeper
+/// }
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// assert_eq!(remaining_stack(), None);
+/// let handle = runtime.spawn(|| depth(100));
+/// runtime.run();
+/// assert!(handle.join().unwrap() > 0);
+/// ```
+pub fn remaining_stack() -> Option<usize> {
+    let rt = unsafe { get_rt() }.ok()?;
+    let stack = rt.threads[rt.current].stack.as_ref()?;
+    // the address of a local is as good as the stack pointer, give or take the current frame.
+    let here = 0_u8;
+    let sp = ptr::addr_of!(here) as usize;
+    Some(sp.saturating_sub(stack.as_ptr() as usize))
+}
+
+/// ensure_sufficient_stack calls f if at least bytes are left on the stack of the calling
+/// thread, see remaining_stack, and panics otherwise, so that a recursion deeper than the
+/// stack allows fails with a panic which can be caught by joining the task, rather than a stack
+/// overflow aborting the process. On the base thread and outside the runtime it always calls f.
+///
+/// ```
+/// use green_threads::{ensure_sufficient_stack, JoinError, Runtime};
+///
+/// fn recurse(n: usize) -> usize {
+///     ensure_sufficient_stack(16 * 1024, || recurse(n + 1) + 1)
+/// }
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let handle = runtime.spawn_with_stack(64 * 1024, || recurse(0)).unwrap();
+/// runtime.run();
+/// assert!(matches!(handle.join(), Err(JoinError::Panicked(_))));
+/// ```
+pub fn ensure_sufficient_stack<R>(bytes: usize, f: impl FnOnce() -> R) -> R {
+    if let Some(remaining) = remaining_stack() {
+        if remaining < bytes {
+            panic!(
+                "not enough stack left: {} bytes remaining, {} needed",
+                remaining, bytes
+            );
+        }
+    }
+    f()
+}
+
+/// current_name returns the name of the spawned thread calling it. Unnamed threads are called
+/// "thread-<id>". It returns None if it's called from the base thread or outside the runtime.
+pub fn current_name() -> Option<String> {
+    let rt = unsafe { get_rt() }.ok()?;
+    if rt.current == 0 {
+        return None;
+    }
+    let current = &rt.threads[rt.current];
+    match &current.name {
+        Some(name) => Some(name.clone()),
+        None => Some(format!("thread-{}", current.id)),
+    }
+}
+
+/// yield_thread is a helper function that lets us call yield from an arbitrary place in our code.
+/// It returns true if another task ran in the meantime, and false if the caller was the only
+/// task ready to run, in which case spin loops may rather back off. It unwinds the calling task
+/// if it has been aborted, and panics if no runtime is initialized on the calling OS thread:
+///
+/// ```should_panic
+/// // panics with "failed to yield: runtime is not initialized on this thread".
+/// green_threads::yield_thread();
+/// ```
+pub fn yield_thread() -> bool {
+    match try_yield() {
+        Ok(switched) => switched,
+        Err(err) => panic!("failed to yield: {}", err),
+    }
+}
+
+/// yield_thread_front is like yield_thread, but the caller goes back to the front of the line
+/// rather than the back: the next thread in line runs until it yields, and then the caller runs
+/// again, ahead of every other ready thread. With yield_thread, every other ready thread gets a
+/// turn before the caller runs again. It suits latency-sensitive loops which only want to let
+/// something else make progress now and then, but other threads of the same priority get a turn
+/// only every other yield of the caller, and are starved by several threads doing it. It
+/// returns false and panics like yield_thread.
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+///
+/// use green_threads::{yield_thread, yield_thread_front, Runtime};
+///
+/// fn order(front: bool) -> String {
+///     let mut runtime = Runtime::new();
+///     runtime.init();
+///     let log = Arc::new(Mutex::new(String::new()));
+///     for name in ["a", "b", "c"] {
+///         let log = log.clone();
+///         runtime.spawn(move || {
+///             for _ in 0..3 {
+///                 log.lock().unwrap().push_str(name);
+///                 if front && name == "a" {
+///                     yield_thread_front();
+///                 } else {
+///                     yield_thread();
+///                 }
+///             }
+///         });
+///     }
+///     runtime.run();
+///     let log = log.lock().unwrap();
+///     log.clone()
+/// }
+///
+/// assert_eq!(order(false), "abcabcabc");
+/// assert_eq!(order(true), "abacabcbc");
+/// ```
+pub fn yield_thread_front() -> bool {
+    let (switched, cancelled) = unsafe {
+        let rt = match get_rt() {
+            Ok(rt) => rt,
+            Err(err) => panic!("failed to yield: {}", err),
+        };
+        rt.idle_yields = 0;
+        rt.give_back = Some(rt.current);
+        let switched = rt.yield_switched();
+        (switched, rt.threads[rt.current].is_cancelled())
+    };
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+    switched
+}
+
+/// yield_to is like yield_thread, but it resumes the thread of the task id rather than the next
+/// one in line, e.g. to hand a lock over to a task waiting for it, or to pass values along a
+/// pipeline of coroutines. A task which is still queued starts right away if there is a thread
+/// for it. The caller stays ready and runs again in its usual turn. Since the runtime runs all
+/// of its threads on one OS thread, there is never a task to migrate from somewhere else.
+///
+/// It returns an error without yielding if the task has finished or never existed, if it's
+/// sleeping or waiting, if it's the caller itself, or if no thread is available to start it.
+/// Overusing it bypasses priorities and the round robin order, so other threads may wait longer.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime, like yield_thread.
+///
+/// # Examples
+///
+/// Two tasks handing over to each other alternate strictly, even with other tasks ready:
+///
+/// ```
+/// use green_threads::{yield_thread, yield_to, Runtime};
+/// use std::sync::{Arc, Mutex};
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let log = Arc::new(Mutex::new(Vec::new()));
+/// for _ in 0..3 {
+///     runtime.spawn(|| {
+///         for _ in 0..10 {
+///             yield_thread();
+///         }
+///     });
+/// }
+/// let peer = Arc::new(Mutex::new(None));
+/// let (ping_log, ping_peer) = (log.clone(), peer.clone());
+/// let ping = runtime.spawn(move || {
+///     for i in 0..3 {
+///         ping_log.lock().unwrap().push(("ping", i));
+///         let pong = ping_peer.lock().unwrap().unwrap();
+///         yield_to(pong).unwrap();
+///     }
+/// });
+/// let ping = ping.id();
+/// let pong_log = log.clone();
+/// let pong = runtime.spawn(move || {
+///     for i in 0..3 {
+///         pong_log.lock().unwrap().push(("pong", i));
+///         if i < 2 {
+///             yield_to(ping).unwrap();
+///         }
+///     }
+/// });
+/// *peer.lock().unwrap() = Some(pong.id());
+/// runtime.run();
+/// let log = log.lock().unwrap();
+/// let expected: Vec<_> = (0..3).flat_map(|i| [("ping", i), ("pong", i)]).collect();
+/// assert_eq!(*log, expected);
+/// ```
+pub fn yield_to(id: TaskId) -> Result<(), Error> {
+    match unsafe { get_rt() } {
+        Ok(rt) => rt.prefer(id)?,
+        Err(err) => panic!("failed to yield: {}", err),
+    }
+    yield_thread();
+    Ok(())
+}
+
+/// consume_budget counts an operation of the calling task on a runtime primitive, and yields
+/// once the task has used up its budget of 128 operations since it last yielded, or its time
+/// slice if preemption is enabled. Channels, mutexes, semaphores and JoinHandle::join call it,
+/// so a task which always finds them ready still lets other threads run now and then, and
+/// libraries can call it for their own primitives. Since it mostly just counts down, a hot loop
+/// can also call it on every iteration to yield now and then rather than on every iteration.
+/// Like yield_thread, it unwinds the calling task if it has been aborted when it yields.
+/// Outside a runtime it does nothing.
+///
+/// ```
+/// use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use green_threads::{channel, yield_thread, Runtime};
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let counter = Arc::new(AtomicUsize::new(0));
+/// let done = Arc::new(AtomicBool::new(false));
+/// let (count, stop) = (counter.clone(), done.clone());
+/// runtime.spawn(move || {
+///     let (tx, rx) = channel();
+///     // never yields explicitly, and every recv finds a value right away.
+///     for i in 0..1000 {
+///         tx.send(i).unwrap();
+///         rx.recv().unwrap();
+///     }
+///     assert!(count.load(Ordering::Relaxed) > 0);
+///     stop.store(true, Ordering::Relaxed);
+/// });
+/// runtime.spawn(move || {
+///     while !done.load(Ordering::Relaxed) {
+///         counter.fetch_add(1, Ordering::Relaxed);
+///         yield_thread();
+///     }
+/// });
+/// runtime.run();
+/// ```
+pub fn consume_budget() {
+    let exhausted = match unsafe { get_rt() } {
+        Ok(rt) => {
+            rt.budget = rt.budget.saturating_sub(1);
+            rt.budget == 0 || rt.timer.as_ref().is_some_and(Timer::expired)
+        }
+        Err(_) => false,
+    };
+    if exhausted {
+        yield_thread();
+    }
+}
+
+/// preemption_point yields if the calling thread has used up its time slice, when the runtime
+/// is built with RuntimeBuilder::preemption, and does nothing otherwise. Long loops which don't
+/// yield can call it, usually through the preemption_point macro, to let other threads run.
+/// Like yield_thread, it unwinds the calling task if it has been aborted when it yields.
+pub fn preemption_point() {
+    let expired = match unsafe { get_rt() } {
+        Ok(rt) => rt.timer.as_ref().is_some_and(Timer::expired),
+        Err(_) => false,
+    };
+    if expired {
+        yield_thread();
+    }
+}
+
+/// try_yield is like yield_thread, but fails with Error::NotInitialized instead of panicking if
+/// no runtime is initialized on the calling OS thread.
+pub fn try_yield() -> Result<bool, Error> {
+    let (switched, cancelled) = unsafe {
+        let rt = get_rt()?;
+        rt.idle_yields = 0;
+        let switched = rt.yield_switched();
+        (switched, rt.threads[rt.current].is_cancelled())
+    };
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+    Ok(switched)
+}
+
+/// sleep suspends the calling thread for at least duration, letting other threads run in the
+/// meantime. If every thread is sleeping, the OS thread sleeps until the earliest deadline
+/// instead of spinning. sleep with a zero duration is the same as yield_thread, and outside a
+/// runtime it falls back to std::thread::sleep. Like yield_thread, it unwinds the calling task
+/// if it has been aborted.
+pub fn sleep(duration: Duration) {
+    if duration == Duration::ZERO {
+        yield_thread();
+        return;
+    }
+    let cancelled = unsafe {
+        let rt = match get_rt() {
+            Ok(rt) => rt,
+            Err(_) => return thread::sleep(duration),
+        };
+        let current = rt.current;
+        rt.threads[current].state = State::Sleeping;
+        rt.idle_yields = 0;
+        rt.timers
+            .push(Reverse((Instant::now() + duration, current)));
+        rt.t_yield();
+        rt.threads[rt.current].is_cancelled()
+    };
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+/// park blocks the calling task until unpark is called with its id, like std::thread::park for
+/// green threads. The thread isn't scheduled at all in the meantime, so waiting this way doesn't
+/// spin, and other threads run until one of them unparks it. If the task was unparked before it
+/// parks, park returns right away, and unparking it several times before it parks only counts
+/// once. It may also return when nothing else is left to run, so callers should check their
+/// condition again in a loop. On the base thread, which has no id to unpark it by, it's the same
+/// as yield_thread. Like yield_thread, it unwinds the calling task if it has been aborted, and
+/// panics if no runtime is initialized on the calling OS thread.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use green_threads::{park, unpark, Runtime};
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let turn = Arc::new(AtomicUsize::new(0));
+/// let waiter_turn = turn.clone();
+/// let waiter = runtime.spawn(move || {
+///     // unparked by the other task once it's done its part.
+///     while waiter_turn.load(Ordering::Acquire) == 0 {
+///         park();
+///     }
+///     waiter_turn.fetch_add(1, Ordering::AcqRel)
+/// });
+/// let id = waiter.id();
+/// runtime.spawn(move || {
+///     turn.store(1, Ordering::Release);
+///     unpark(id).unwrap();
+/// });
+/// runtime.run();
+/// assert_eq!(waiter.join().unwrap(), 1);
+/// ```
+pub fn park() {
+    let cancelled = unsafe {
+        let rt = match get_rt() {
+            Ok(rt) => rt,
+            Err(err) => panic!("failed to park: {}", err),
+        };
+        let current = rt.current;
+        let id = match rt.threads[current].task_id {
+            Some(id) => id,
+            None => {
+                yield_thread();
+                return;
+            }
+        };
+        if !rt.unparked.remove(&id) {
+            rt.threads[current].state = State::Parked;
+            rt.idle_yields = 0;
+            rt.t_yield();
+            // t_yield returns without switching if nothing else can run.
+            rt.threads[current].state = State::Running;
+            rt.unparked.remove(&id);
+        }
+        rt.threads[current].is_cancelled()
+    };
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+/// unpark makes the task id ready again if it's parked, or else makes its next park return
+/// right away. It doesn't yield. It can only be called on the OS thread the runtime runs on,
+/// and fails with Error::NotInitialized anywhere else, or with Error::NoSuchTask if the task
+/// has finished or never existed.
+pub fn unpark(id: TaskId) -> Result<(), Error> {
+    unsafe { get_rt() }?.unpark(id)
+}
+
+/// shutdown shuts down the runtime initialized on the calling OS thread, like Runtime::shutdown.
+/// The calling task is aborted as well, so it unwinds at its next yield_thread. It panics if no
+/// runtime is initialized on the calling OS thread.
+pub fn shutdown() {
+    match unsafe { get_rt() } {
+        Ok(rt) => rt.shutdown(),
+        Err(err) => panic!("failed to shut down: {}", err),
+    }
+}
+
+/// is_shutting_down tells whether the runtime initialized on the calling OS thread is shutting
+/// down, so a task can wrap up before it reaches its next yield, where it's aborted. It returns
+/// false outside a runtime.
+pub fn is_shutting_down() -> bool {
+    match unsafe { get_rt() } {
+        Ok(rt) => rt.is_stopping(),
+        Err(_) => false,
+    }
+}
+
+/// in_runtime tells whether a runtime is initialized on the calling OS thread, i.e. whether
+/// yield_thread can be called. Library code can fall back to std::thread::yield_now otherwise.
+pub fn in_runtime() -> bool {
+    !rt_ptr().is_null()
+}
+
+/// spawn spawns a closure on the runtime initialized on the calling OS thread, which makes it
+/// usable from inside a running thread, e.g. to spawn children and join them. Like
+/// Runtime::spawn, the closure is queued if all the threads are taken. It panics if no runtime
+/// is initialized on the calling OS thread, or if it's shutting down.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match try_spawn(f) {
+        Ok(handle) => handle,
+        Err(err) => panic!("failed to spawn: {}", err),
+    }
+}
+
+/// try_spawn is like spawn, but fails with Error::NotInitialized instead of panicking if no
+/// runtime is initialized on the calling OS thread, with Error::ShuttingDown if the runtime is
+/// shutting down, or with Error::TooManyTasks if the runtime has reached its
+/// RuntimeBuilder::max_tasks.
+pub fn try_spawn<F, T>(f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    unsafe {
+        let rt = get_rt()?;
+        rt.check_running()?;
+        rt.check_capacity()?;
+        let stack_size = rt.default_stack_size;
+        Ok(rt.spawn_unchecked(None, stack_size, f))
+    }
+}
+
+/// spawn_blocking runs a closure which blocks, like reading a file or calling into a C library,
+/// on an OS thread of the runtime's blocking pool instead of the OS thread running the runtime,
+/// so other threads keep running in the meantime. Joining the returned JoinHandle yields until
+/// the closure has finished. It can't be aborted once it started. It panics if no runtime is
+/// initialized on the calling OS thread.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let rt = match unsafe { get_rt() } {
+        Ok(rt) => rt,
+        Err(err) => panic!("failed to spawn a blocking task: {}", err),
+    };
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let packet = Arc::new(Mutex::new(Packet {
+        result: None,
+        done: false,
+        waiter: None,
+        stack_used: None,
+    }));
+    let completion = Completion(packet.clone());
+    let aborted = cancelled.clone();
+    rt.blocking.execute(Box::new(move || {
+        if aborted.load(Ordering::Relaxed) {
+            return;
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panicked);
+        completion.complete(result);
+    }));
+    JoinHandle {
+        id: TaskId::next(),
+        packet,
+        cancelled,
+    }
+}
+
+// yield_waiting is like yield_thread, but it yields from a loop waiting for something another
+// thread has to do, see Runtime::t_wait.
+pub(crate) fn yield_waiting(retry: bool) -> bool {
+    let (switched, cancelled) = unsafe {
+        let rt = match get_rt() {
+            Ok(rt) => rt,
+            Err(err) => panic!("failed to yield: {}", err),
+        };
+        let switched = rt.t_wait(retry);
+        (switched, rt.threads[rt.current].is_cancelled())
+    };
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+    switched
+}
+
+// with_locals calls f with the task locals of the calling thread, which is the base thread
+// outside spawned tasks.
+pub(crate) fn with_locals<F, R>(f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut HashMap<usize, Box<dyn Any>>) -> R,
+{
+    let rt = unsafe { get_rt() }?;
+    let current = rt.current;
+    Ok(f(&mut rt.threads[current].locals))
+}
+
+// current_task is the id of the calling task together with a Handle of its runtime, to unpark
+// it from another OS thread, or None outside a task.
+#[cfg(target_os = "linux")]
+pub(crate) fn current_task() -> Option<(TaskId, Handle)> {
+    let rt = unsafe { get_rt() }.ok()?;
+    let id = rt.threads[rt.current].task_id?;
+    Some((id, rt.handle()))
+}
+
+// run_next makes the thread running the task id run at the next yield of the calling thread, if
+// the caller is on the runtime. Other OS threads can't reach the runtime, so it does nothing there.
+pub(crate) fn run_next(id: TaskId) {
+    if let Ok(rt) = unsafe { get_rt() } {
+        let _ = rt.prefer(id);
+    }
+}
+
+// in_current_guard tells whether addr is in the guard page of the running thread's stack. It's
+// called by the handler of a fault, so it must neither allocate nor lock.
+#[cfg(target_os = "linux")]
+pub(crate) fn in_current_guard(addr: usize) -> bool {
+    match unsafe { get_rt() } {
+        Ok(rt) => match &rt.threads[rt.current].stack {
+            Some(stack) => stack.is_guard(addr),
+            None => false,
+        },
+        Err(_) => false,
+    }
+}
+
+// rt_ptr returns the runtime init registered on the calling OS thread, or null.
+fn rt_ptr() -> *mut Runtime {
+    RUNTIME.with(|rt| rt.get())
+}
+
+// get_rt returns the runtime registered by init on the calling OS thread, checking that there
+// is one. Other threads use the runtime while the caller is switched out, so anything read from
+// it before a switch must be read again after.
+unsafe fn get_rt() -> Result<&'static mut Runtime, Error> {
+    let rt = rt_ptr();
+    if rt.is_null() {
+        return Err(Error::NotInitialized);
+    }
+    Ok(&mut *rt)
+}
@@ -0,0 +1,251 @@
+//! stack holds the memory threads run on. On Linux a stack is mapped with a guard page below it,
+//! so a thread overflowing its stack faults instead of silently overwriting whatever lies below,
+//! and the fault is reported as a stack overflow in a green thread. Other platforms fall back to
+//! a plain heap allocation without any guard, where an overflow is undefined behavior.
+//!
+//! Linux stacks can also be backed by transparent huge pages, which cuts the TLB misses of many
+//! long-lived threads with large stacks.
+
+use std::ops::{Deref, DerefMut};
+
+/// Stack is the memory of a thread's stack, which derefs to its usable bytes.
+pub(crate) struct Stack {
+    #[cfg(target_os = "linux")]
+    map: sys::Mapping,
+    #[cfg(not(target_os = "linux"))]
+    bytes: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl Stack {
+    /// allocate a zeroed stack of size usable bytes. The mapping only reserves them, and the
+    /// kernel commits a page the first time it's touched, so a thread using a few KiB of a large
+    /// stack only costs those. With huge_pages, the usable bytes are rounded up to whole huge
+    /// pages and the kernel is asked to back them with huge pages, which it commits a whole one
+    /// at a time. If it refuses, the stack is backed by regular pages as usual.
+    pub(crate) fn new(size: usize, huge_pages: bool) -> Self {
+        sys::install_handler();
+        Stack {
+            map: sys::Mapping::new(size, huge_pages),
+        }
+    }
+
+    /// whether addr is in the guard page below the stack.
+    pub(crate) fn is_guard(&self, addr: usize) -> bool {
+        self.map.is_guard(addr)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Stack {
+    /// allocate a zeroed stack of size usable bytes. vec! zeroes it through alloc_zeroed, which
+    /// the system allocators serve for sizes this large with fresh pages from the OS, zeroed
+    /// lazily like the Linux mapping. That isn't guaranteed though, and another allocator may
+    /// memset the whole stack. There are no huge pages to ask for.
+    pub(crate) fn new(size: usize, _huge_pages: bool) -> Self {
+        Stack {
+            bytes: vec![0_u8; size],
+        }
+    }
+}
+
+impl Deref for Stack {
+    type Target = [u8];
+
+    #[cfg(target_os = "linux")]
+    fn deref(&self) -> &[u8] {
+        self.map.as_slice()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Stack {
+    #[cfg(target_os = "linux")]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.map.as_mut_slice()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::mem;
+    use std::ptr;
+    use std::slice;
+    use std::sync::Once;
+
+    use crate::runtime::in_current_guard;
+
+    const PROT_NONE: i32 = 0;
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const MAP_PRIVATE: i32 = 0x02;
+    const MAP_ANONYMOUS: i32 = 0x20;
+    const MAP_FAILED: *mut u8 = !0 as *mut u8;
+    const SC_PAGESIZE: i32 = 30;
+    const SIGBUS: i32 = 7;
+    const SIGSEGV: i32 = 11;
+    const SA_SIGINFO: i32 = 0x04;
+    const SA_ONSTACK: i32 = 0x0800_0000;
+    const MADV_HUGEPAGE: i32 = 14;
+    // the size of the transparent huge pages of x86_64 and of aarch64 with 4 KiB pages
+    const HUGE_PAGE: usize = 2 * 1024 * 1024;
+
+    // the struct sigaction of the kernel ABI glibc and musl use on Linux.
+    #[repr(C)]
+    struct SigAction {
+        sa_sigaction: usize,
+        sa_mask: [u64; 16],
+        sa_flags: i32,
+        sa_restorer: usize,
+    }
+
+    // the beginning of siginfo_t, up to si_addr which is all we read.
+    #[repr(C)]
+    struct SigInfo {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_addr: usize,
+    }
+
+    extern "C" {
+        fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, off: i64) -> *mut u8;
+        fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut u8, len: usize) -> i32;
+        fn madvise(addr: *mut u8, len: usize, advice: i32) -> i32;
+        fn sysconf(name: i32) -> i64;
+        fn sigaction(signum: i32, act: *const SigAction, oldact: *mut SigAction) -> i32;
+        fn sigemptyset(set: *mut [u64; 16]) -> i32;
+        fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+        fn abort() -> !;
+    }
+
+    // the handlers which were installed before ours, to pass on the faults which aren't ours.
+    static mut PREV_SIGSEGV: mem::MaybeUninit<SigAction> = mem::MaybeUninit::uninit();
+    static mut PREV_SIGBUS: mem::MaybeUninit<SigAction> = mem::MaybeUninit::uninit();
+    static INSTALL: Once = Once::new();
+
+    /// Mapping is an anonymous mapping of a guard page followed by the usable bytes of a stack,
+    /// which end at the end of the mapping. The usable bytes start at the exact size asked for,
+    /// so the bytes between the guard page and them, if any, are spare. With huge pages, the
+    /// part after the guard page is a whole number of huge pages starting at a huge page
+    /// boundary, since the kernel only backs aligned ones with huge pages.
+    pub(super) struct Mapping {
+        base: *mut u8,
+        map_len: usize,
+        guard_len: usize,
+        len: usize,
+    }
+
+    impl Mapping {
+        pub(super) fn new(len: usize, huge_pages: bool) -> Self {
+            let page = unsafe { sysconf(SC_PAGESIZE) } as usize;
+            let unit = if huge_pages { HUGE_PAGE } else { page };
+            let map_len = page + len.div_ceil(unit) * unit;
+            // map a huge page more than needed to find an aligned start in, and unmap the rest.
+            let slack = if huge_pages { HUGE_PAGE } else { 0 };
+            unsafe {
+                let mapped = mmap(
+                    ptr::null_mut(),
+                    map_len + slack,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                assert!(
+                    mapped != MAP_FAILED,
+                    "failed to map a stack of {} bytes",
+                    len
+                );
+                let mut base = mapped;
+                if huge_pages {
+                    let start = (mapped as usize + page).next_multiple_of(HUGE_PAGE);
+                    base = mapped.add(start - page - mapped as usize);
+                    let head = base as usize - mapped as usize;
+                    if head > 0 {
+                        munmap(mapped, head);
+                    }
+                    if slack > head {
+                        munmap(base.add(map_len), slack - head);
+                    }
+                    // a refusal only means regular pages, so the result doesn't matter.
+                    madvise(base.add(page), map_len - page, MADV_HUGEPAGE);
+                }
+                assert!(
+                    mprotect(base, page, PROT_NONE) == 0,
+                    "failed to protect the guard page of a stack"
+                );
+                Mapping {
+                    base,
+                    map_len,
+                    guard_len: page,
+                    len,
+                }
+            }
+        }
+
+        pub(super) fn is_guard(&self, addr: usize) -> bool {
+            let base = self.base as usize;
+            addr >= base && addr < base + self.guard_len
+        }
+
+        pub(super) fn as_slice(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.base.add(self.map_len - self.len), self.len) }
+        }
+
+        pub(super) fn as_mut_slice(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.base.add(self.map_len - self.len), self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe {
+                munmap(self.base, self.map_len);
+            }
+        }
+    }
+
+    /// install_handler installs the handler of SIGSEGV and SIGBUS once for the process. It runs
+    /// on the alternate signal stack std sets up for its own threads, since the stack of the
+    /// faulting thread is exhausted.
+    pub(super) fn install_handler() {
+        INSTALL.call_once(|| unsafe {
+            let mut action: SigAction = mem::zeroed();
+            action.sa_sigaction = handle_fault as *const () as usize;
+            action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+            sigemptyset(&mut action.sa_mask);
+            sigaction(SIGSEGV, &action, ptr::addr_of_mut!(PREV_SIGSEGV).cast());
+            sigaction(SIGBUS, &action, ptr::addr_of_mut!(PREV_SIGBUS).cast());
+        });
+    }
+
+    // handle_fault aborts with a message if the fault hit the guard page of the running green
+    // thread. Any other fault is handed back to the previous handler, by restoring it and
+    // returning so that the faulting instruction runs again.
+    extern "C" fn handle_fault(signum: i32, info: *mut SigInfo, _context: *mut u8) {
+        unsafe {
+            if in_current_guard((*info).si_addr) {
+                let message = b"fatal runtime error: stack overflow in green thread\n";
+                write(2, message.as_ptr(), message.len());
+                abort();
+            }
+            let prev = if signum == SIGSEGV {
+                ptr::addr_of!(PREV_SIGSEGV)
+            } else {
+                ptr::addr_of!(PREV_SIGBUS)
+            };
+            sigaction(signum, prev.cast(), ptr::null_mut());
+        }
+    }
+}
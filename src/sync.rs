@@ -0,0 +1,437 @@
+//! sync holds cooperative counterparts of the primitives of std::sync, which wait for each other
+//! by yielding to other threads instead of blocking the OS thread running the runtime.
+
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::thread;
+
+use crate::runtime::{consume_budget, current, run_next};
+use crate::task::{wait, TaskId};
+
+/// Mutex is a lock protecting a value, which can be held across yields. While it's held, lock
+/// yields to other threads, and once it's released, the task which has been waiting the longest
+/// is the next one to run. Outside the runtime, e.g. in spawn_blocking, lock parks the calling
+/// OS thread instead. Unlike std::sync::Mutex, it isn't poisoned when a thread panics while
+/// holding it.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use green_threads::{yield_thread, Mutex, Runtime};
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let counter = Arc::new(Mutex::new(0));
+/// for _ in 0..2 {
+///     let counter = counter.clone();
+///     runtime.spawn(move || {
+///         for _ in 0..100 {
+///             let mut count = counter.lock();
+///             let next = *count + 1;
+///             // the other thread runs in the meantime, but it can't lock the counter.
+///             yield_thread();
+///             *count = next;
+///         }
+///     });
+/// }
+/// runtime.run();
+/// assert_eq!(*counter.lock(), 200);
+/// ```
+pub struct Mutex<T: ?Sized> {
+    // a single permit, which is held along with the lock
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// MutexGuard gives access to the value of a locked Mutex, and releases the lock when it's
+/// dropped, including when the thread holding it unwinds.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// create an unlocked mutex protecting value.
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// consume the mutex and return its value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait for the lock and return a guard holding it. On the thread running the runtime it
+    /// yields to other threads while waiting, otherwise it parks the calling OS thread. If all
+    /// the other threads of the runtime are waiting as well, it parks until the lock is released
+    /// from another OS thread. Locking a mutex the calling thread already holds never returns.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        consume_budget();
+        self.semaphore.take();
+        MutexGuard { mutex: self }
+    }
+
+    /// take the lock if it isn't held, without waiting.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if !self.semaphore.try_take() {
+            return None;
+        }
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// return a mutable reference to the value. No lock is needed since the mutex is borrowed
+    /// mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.release();
+    }
+}
+
+/// Condvar lets threads wait for a condition on the value of a Mutex, without yielding in a loop
+/// to check it. wait releases the lock until another thread calls notify_one or notify_all,
+/// yielding to other threads in the meantime, or parking the calling OS thread outside the
+/// runtime. A waiting thread may also wake up although nothing changed, so the condition has to
+/// be checked again after each wait, which wait_while does.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use green_threads::{Condvar, Mutex, Runtime};
+///
+/// const CAPACITY: usize = 2;
+///
+/// struct Buffer {
+///     items: Mutex<Vec<usize>>,
+///     not_empty: Condvar,
+///     not_full: Condvar,
+/// }
+///
+/// let mut runtime = Runtime::new();
+/// runtime.init();
+/// let buffer = Arc::new(Buffer {
+///     items: Mutex::new(Vec::new()),
+///     not_empty: Condvar::new(),
+///     not_full: Condvar::new(),
+/// });
+/// let mut consumers = Vec::new();
+/// for producer in 0..2 {
+///     let shared = buffer.clone();
+///     runtime.spawn(move || {
+///         for i in 0..10 {
+///             let items = shared.items.lock();
+///             let mut items = shared.not_full.wait_while(items, |items| items.len() == CAPACITY);
+///             items.push(producer * 10 + i);
+///             shared.not_empty.notify_one();
+///         }
+///     });
+///     let shared = buffer.clone();
+///     consumers.push(runtime.spawn(move || {
+///         let mut sum = 0;
+///         for _ in 0..10 {
+///             let items = shared.items.lock();
+///             let mut items = shared.not_empty.wait_while(items, |items| items.is_empty());
+///             sum += items.pop().unwrap();
+///             shared.not_full.notify_one();
+///         }
+///         sum
+///     }));
+/// }
+/// runtime.run();
+/// let sum: usize = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+/// assert_eq!(sum, (0..20).sum());
+/// ```
+pub struct Condvar {
+    waiters: StdMutex<VecDeque<Waiter>>,
+}
+
+// Waiter is a thread waiting in Condvar::wait, which is notified by setting its flag and waking
+// it up.
+struct Waiter {
+    notified: Arc<AtomicBool>,
+    task: Option<TaskId>,
+    // the OS thread to unpark, in case it's parked in wait
+    thread: thread::Thread,
+}
+
+impl Waiter {
+    fn notify(self) {
+        self.notified.store(true, Ordering::Release);
+        if let Some(task) = self.task {
+            run_next(task);
+        }
+        self.thread.unpark();
+    }
+}
+
+// Waiting removes a waiter which is never notified from its Condvar, e.g. when its task is
+// aborted while waiting, so that a notification isn't wasted on it.
+struct Waiting<'a> {
+    condvar: &'a Condvar,
+    notified: Arc<AtomicBool>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.notified.load(Ordering::Acquire) {
+            let mut waiters = self.condvar.waiters.lock().unwrap();
+            waiters.retain(|w| !Arc::ptr_eq(&w.notified, &self.notified));
+        }
+    }
+}
+
+impl Condvar {
+    /// create a condition variable nothing is waiting on.
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: StdMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Release the lock of guard and wait to be notified, then lock the mutex again and return
+    /// its guard. Like JoinHandle::join, it yields to other threads while waiting on the thread
+    /// running the runtime, and parks the calling OS thread otherwise.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let waiting = Waiting {
+            condvar: self,
+            notified: Arc::new(AtomicBool::new(false)),
+        };
+        // the waiter is added before the lock is released, so a notification sent by whoever
+        // takes it next can't be missed.
+        self.waiters.lock().unwrap().push_back(Waiter {
+            notified: waiting.notified.clone(),
+            task: current(),
+            thread: thread::current(),
+        });
+        drop(guard);
+        wait(|| waiting.notified.load(Ordering::Acquire).then_some(()));
+        mutex.lock()
+    }
+
+    /// wait_while waits as long as condition returns true for the value of the mutex, checking
+    /// it before each wait, and returns the guard once it returns false.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// wake up the thread which has been waiting the longest, if any.
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock().unwrap().pop_front();
+        if let Some(waiter) = waiter {
+            waiter.notify();
+        }
+    }
+
+    /// wake up all the waiting threads.
+    pub fn notify_all(&self) {
+        let waiters = mem::take(&mut *self.waiters.lock().unwrap());
+        for waiter in waiters {
+            waiter.notify();
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+/// Semaphore limits how many threads can be in a section at once, with a number of permits which
+/// are taken to enter it and returned when leaving. While no permit is available, acquire yields
+/// to other threads, and once one is returned, the task which has been waiting the longest is
+/// the next one to run. Outside the runtime, acquire parks the calling OS thread instead.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use green_threads::{yield_thread, Runtime, Semaphore};
+///
+/// let mut runtime = Runtime::builder().max_threads(10).build();
+/// runtime.init();
+/// let semaphore = Arc::new(Semaphore::new(3));
+/// let inside = Arc::new(AtomicUsize::new(0));
+/// let most = Arc::new(AtomicUsize::new(0));
+/// for _ in 0..10 {
+///     let (semaphore, inside, most) = (semaphore.clone(), inside.clone(), most.clone());
+///     runtime.spawn(move || {
+///         let _permit = semaphore.acquire();
+///         let now = inside.fetch_add(1, Ordering::Relaxed) + 1;
+///         most.fetch_max(now, Ordering::Relaxed);
+///         yield_thread();
+///         inside.fetch_sub(1, Ordering::Relaxed);
+///     });
+/// }
+/// runtime.run();
+/// assert_eq!(most.load(Ordering::Relaxed), 3);
+/// ```
+pub struct Semaphore {
+    state: StdMutex<Permits>,
+}
+
+// Permits is the number of permits a Semaphore has available, and who is waiting for one in the
+// order they started waiting.
+struct Permits {
+    available: usize,
+    tasks: VecDeque<TaskId>,
+    // OS threads parked in acquire, waiting to be unparked by release
+    threads: Vec<thread::Thread>,
+}
+
+/// SemaphorePermit is a permit taken from a Semaphore, which is returned when it's dropped,
+/// including when the thread holding it unwinds.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// create a semaphore with permits available.
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: StdMutex::new(Permits {
+                available: permits,
+                tasks: VecDeque::new(),
+                threads: Vec::new(),
+            }),
+        }
+    }
+
+    /// Wait for a permit and take it. On the thread running the runtime it yields to other
+    /// threads while waiting, so they keep running, otherwise it parks the calling OS thread.
+    /// If all the other threads of the runtime are waiting as well, it parks until a permit is
+    /// returned from another OS thread.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        consume_budget();
+        self.take();
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// take a permit if one is available, without waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if !self.try_take() {
+            return None;
+        }
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// the number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().available
+    }
+
+    /// Add a permit, waking up the task which has been waiting the longest for one, and the OS
+    /// threads parked waiting for one. It's how a permit given up with SemaphorePermit::forget
+    /// is returned, and it can also raise the number of permits above the one the semaphore was
+    /// created with.
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.available += 1;
+        // whoever gets to the permit first takes it, and the others wait again.
+        if let Some(task) = state.tasks.pop_front() {
+            run_next(task);
+        }
+        for thread in state.threads.drain(..) {
+            thread.unpark();
+        }
+    }
+
+    // take waits for a permit and takes it, without a SemaphorePermit to return it.
+    fn take(&self) {
+        let task = current();
+        let me = thread::current();
+        wait(|| {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                // we may have been woken up, or have taken the permit before our turn.
+                if let Some(task) = task {
+                    state.tasks.retain(|&id| id != task);
+                }
+                return Some(());
+            }
+            if let Some(task) = task {
+                if !state.tasks.contains(&task) {
+                    state.tasks.push_back(task);
+                }
+            }
+            // the thread running the runtime is registered once for all of its tasks.
+            if state.threads.iter().all(|t| t.id() != me.id()) {
+                state.threads.push(me.clone());
+            }
+            None
+        })
+    }
+
+    // try_take takes a permit if one is available, without a SemaphorePermit to return it.
+    fn try_take(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.available == 0 {
+            return false;
+        }
+        state.available -= 1;
+        true
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// give up the permit without returning it, so the semaphore has one permit less, until
+    /// Semaphore::release adds one back.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
@@ -0,0 +1,179 @@
+//! task holds the handles to spawned tasks: their ids, and how their results get back to the
+//! caller.
+
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::runtime::{consume_budget, in_runtime, yield_waiting};
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// TaskId identifies a spawned task. Unlike the id of the thread running it, which is reused once
+/// the thread is available again, it's unique for the whole process.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    pub(crate) fn next() -> Self {
+        TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// JoinHandle is an owned permission to wait for a spawned thread and take its result.
+/// Dropping it detaches the thread, and the result is freed once the thread finishes.
+pub struct JoinHandle<T> {
+    pub(crate) id: TaskId,
+    pub(crate) packet: Arc<Mutex<Packet<T>>>,
+    pub(crate) cancelled: Arc<AtomicBool>,
+}
+
+/// JoinError is returned by JoinHandle::join when the thread didn't finish normally.
+#[derive(Debug)]
+pub enum JoinError {
+    /// the task was aborted before it finished.
+    Cancelled,
+    /// the task panicked, with the payload of the panic.
+    Panicked(Box<dyn Any + Send>),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(_) => write!(f, "task panicked"),
+        }
+    }
+}
+
+impl error::Error for JoinError {}
+
+// Cancelled is the panic payload yield_thread unwinds an aborted task with.
+pub(crate) struct Cancelled;
+
+// Packet is shared by a JoinHandle and the closure running on the spawned thread.
+pub(crate) struct Packet<T> {
+    pub(crate) result: Option<Result<T, JoinError>>,
+    pub(crate) done: bool,
+    // the OS thread parked in join, waiting to be unparked with the result
+    pub(crate) waiter: Option<thread::Thread>,
+    // how much of its stack the task used, if the runtime measured it
+    pub(crate) stack_used: Option<usize>,
+}
+
+// Completion is the spawned thread's end of a Packet. It's owned by the boxed closure, so if it's
+// dropped before completing, the task has been removed before it started.
+pub(crate) struct Completion<T>(pub(crate) Arc<Mutex<Packet<T>>>);
+
+impl<T> Completion<T> {
+    pub(crate) fn set_stack_used(&self, used: usize) {
+        self.0.lock().unwrap().stack_used = Some(used);
+    }
+
+    pub(crate) fn complete(&self, result: Result<T, JoinError>) {
+        let mut packet = self.0.lock().unwrap();
+        packet.result = Some(result);
+        packet.done = true;
+        if let Some(waiter) = packet.waiter.take() {
+            waiter.unpark();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if !self.0.lock().unwrap().done {
+            self.complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// the id of the spawned task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// the number of bytes of its stack the task used at most, once it has finished, if the
+    /// runtime is built with RuntimeBuilder::measure_stack_usage. It's None until then, and for
+    /// the closures of spawn_blocking, which don't run on a stack of the runtime.
+    pub fn stack_used(&self) -> Option<usize> {
+        self.packet.lock().unwrap().stack_used
+    }
+
+    /// Wait for the thread to finish and return its result. It returns immediately if the thread has
+    /// already finished. On the thread running the runtime it yields to other threads while
+    /// waiting, otherwise it parks the calling OS thread until the result is available.
+    /// If all the other threads of the runtime are waiting as well, it parks until a task of
+    /// spawn_blocking finishes. It returns JoinError::Cancelled if the task was aborted before
+    /// it finished, or JoinError::Panicked if it panicked.
+    pub fn join(self) -> Result<T, JoinError> {
+        consume_budget();
+        wait(|| self.poll())
+    }
+
+    // poll takes the result if the thread has finished, otherwise it registers the calling OS
+    // thread to be unparked with the result.
+    fn poll(&self) -> Option<Result<T, JoinError>> {
+        let mut packet = self.packet.lock().unwrap();
+        if packet.done {
+            return packet.result.take();
+        }
+        packet.waiter = Some(thread::current());
+        None
+    }
+
+    /// Abort the task. If it hasn't started yet, it's removed before it ever runs. Otherwise the
+    /// next yield_thread it calls unwinds it, running its destructors, instead of returning, and
+    /// so does a sleep or park it's in already, without waiting for its deadline or unpark.
+    /// Either way join returns JoinError::Cancelled, unless the task finishes first, in which
+    /// case aborting it does nothing.
+    pub fn abort(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// join_all waits for every thread of handles to finish, like calling join on each of them, and
+/// returns their results in the same order.
+pub fn join_all<T>(handles: Vec<JoinHandle<T>>) -> Vec<Result<T, JoinError>> {
+    handles.into_iter().map(JoinHandle::join).collect()
+}
+
+/// wait_any waits for the first thread of handles to finish, and returns its index in handles
+/// and its result, with the handles left in their order. It waits the same way as
+/// JoinHandle::join, and if several threads have already finished, the first of them in
+/// handles is picked. It panics if handles is empty.
+pub fn wait_any<T>(
+    mut handles: Vec<JoinHandle<T>>,
+) -> (usize, Result<T, JoinError>, Vec<JoinHandle<T>>) {
+    assert!(!handles.is_empty(), "wait_any needs at least one handle.");
+    let (index, result) = wait(|| {
+        handles
+            .iter()
+            .enumerate()
+            .find_map(|(i, handle)| handle.poll().map(|result| (i, result)))
+    });
+    handles.remove(index);
+    (index, result, handles)
+}
+
+// wait calls poll until it returns something, and poll registers the calling OS thread to be
+// unparked by whoever makes it succeed if it fails. On the thread running the runtime it yields
+// to other threads in between, and parks only once they're all waiting too, so that only another
+// OS thread or a timer can make progress. Otherwise it parks the calling OS thread.
+pub(crate) fn wait<R>(mut poll: impl FnMut() -> Option<R>) -> R {
+    let on_runtime = in_runtime();
+    let mut retry = false;
+    loop {
+        if let Some(result) = poll() {
+            return result;
+        }
+        if !on_runtime || !yield_waiting(retry) {
+            thread::park();
+        }
+        retry = true;
+    }
+}
@@ -0,0 +1,79 @@
+//! align checks tasks start on a stack aligned the way the ABI wants, by doing what breaks on a
+//! misaligned one: formatting floats, keeping over-aligned locals and loading them with aligned
+//! SSE instructions, whatever the size of their stack.
+
+use std::hint::black_box;
+
+use green_threads::{yield_thread, Runtime};
+
+#[repr(align(16))]
+struct Aligned([f64; 2]);
+
+// aligned_work does the work and returns what it formatted, checking the local is aligned.
+fn aligned_work(i: usize) -> String {
+    let local = black_box(Aligned([i as f64 / 3.0, 1e-300 * i as f64]));
+    assert_eq!(&local as *const Aligned as usize % 16, 0);
+    yield_thread();
+    format!("{:.5} {:e} {}", local.0[0], local.0[1], (i as f64).sqrt())
+}
+
+#[test]
+fn tasks_start_on_an_aligned_stack() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handles: Vec<_> = (0..8)
+        .map(|i| runtime.spawn(move || aligned_work(i)))
+        .collect();
+    runtime.run();
+    let formatted: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(formatted[3], "1.00000 3e-300 1.7320508075688772");
+}
+
+#[test]
+fn tasks_with_an_odd_stack_size_start_aligned() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handles: Vec<_> = [64 * 1024 + 3, 32 * 1024 + 9, 16 * 1024 + 1]
+        .iter()
+        .enumerate()
+        .map(|(i, &size)| {
+            runtime
+                .spawn_with_stack(size, move || aligned_work(i))
+                .unwrap()
+        })
+        .collect();
+    runtime.run();
+    let formatted: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(formatted[1], "0.33333 1e-300 1");
+}
+
+// sse_sum loads local with an instruction which faults unless its address is 16 byte aligned.
+#[cfg(target_arch = "x86_64")]
+fn sse_sum(i: usize) -> f64 {
+    use std::arch::x86_64::{_mm_add_pd, _mm_load_pd, _mm_store_pd};
+
+    let local = black_box(Aligned([i as f64, 0.5]));
+    let mut sum = Aligned([0.0; 2]);
+    unsafe {
+        let v = _mm_load_pd(black_box(local.0.as_ptr()));
+        yield_thread();
+        _mm_store_pd(sum.0.as_mut_ptr(), _mm_add_pd(v, v));
+    }
+    sum.0[0] + sum.0[1]
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn tasks_call_aligned_sse_intrinsics() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let mut handles: Vec<_> = (0..4).map(|i| runtime.spawn(move || sse_sum(i))).collect();
+    handles.push(
+        runtime
+            .spawn_with_stack(32 * 1024 + 5, || sse_sum(4))
+            .unwrap(),
+    );
+    runtime.run();
+    let sums: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(sums, [1.0, 3.0, 5.0, 7.0, 9.0]);
+}
@@ -0,0 +1,126 @@
+//! api drives spawn, yield_thread and run through the public API only, the way a crate
+//! depending on green-threads would.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use green_threads::{current, current_name, in_runtime, sleep, spawn, yield_thread, Runtime};
+
+#[test]
+fn run_returns_the_results_of_spawned_tasks() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handles: Vec<_> = (0..3).map(|i| runtime.spawn(move || i * 10)).collect();
+    runtime.run();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, [0, 10, 20]);
+}
+
+#[test]
+fn yielding_tasks_take_turns() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for name in ["a", "b"] {
+        let log = log.clone();
+        runtime.spawn(move || {
+            for i in 0..3 {
+                log.lock().unwrap().push(format!("{}{}", name, i));
+                yield_thread();
+            }
+        });
+    }
+    runtime.run();
+    assert_eq!(*log.lock().unwrap(), ["a0", "b0", "a1", "b1", "a2", "b2"]);
+}
+
+#[test]
+fn tasks_spawn_and_join_children() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let root = runtime.spawn(|| {
+        let children: Vec<_> = (1..=2).map(|i| spawn(move || i)).collect();
+        children.into_iter().map(|h| h.join().unwrap()).sum::<i32>()
+    });
+    runtime.run();
+    assert_eq!(root.join().unwrap(), 3);
+}
+
+#[test]
+fn tasks_know_where_they_run() {
+    assert!(!in_runtime());
+    let mut runtime = Runtime::new();
+    runtime.init();
+    assert!(in_runtime());
+    assert_eq!(current(), None);
+    let handle = runtime.spawn_named("worker", || (current(), current_name()));
+    let id = handle.id();
+    runtime.run();
+    let (task, name) = handle.join().unwrap();
+    assert_eq!(task, Some(id));
+    assert_eq!(name.as_deref(), Some("worker"));
+}
+
+#[test]
+fn a_runtime_runs_again_after_run_returns() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let first = runtime.spawn(|| 1);
+    runtime.run();
+    let second = runtime.spawn(|| 2);
+    runtime.run();
+    assert_eq!(first.join().unwrap() + second.join().unwrap(), 3);
+    assert_eq!(runtime.stats().completed, 2);
+}
+
+#[test]
+fn run_returns_after_a_single_short_task() {
+    let mut runtime = Runtime::builder().max_threads(1).build();
+    runtime.init();
+    let one = runtime.spawn(|| "one");
+    runtime.run();
+    assert_eq!(one.join().unwrap(), "one");
+    assert_eq!(runtime.stats().completed, 1);
+}
+
+#[test]
+fn run_returns_with_no_tasks_one_task_or_many() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    assert_eq!(runtime.run(), 0);
+    assert_eq!(runtime.stats().spawned, 0);
+
+    let one = runtime.spawn(|| "one");
+    runtime.run();
+    assert_eq!(one.join().unwrap(), "one");
+
+    let many: Vec<_> = (0..500)
+        .map(|i| {
+            runtime.spawn(move || {
+                yield_thread();
+                i
+            })
+        })
+        .collect();
+    runtime.run();
+    let sum: usize = many.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(sum, (0..500).sum());
+    assert_eq!(runtime.stats().completed, 501);
+}
+
+#[test]
+fn a_task_spawned_by_a_handle_starts_while_others_sleep() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.handle();
+    let start = Instant::now();
+    runtime.spawn(|| sleep(Duration::from_millis(500)));
+    let spawner = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.spawn(move || start.elapsed())
+    });
+    runtime.run();
+    let started = spawner.join().unwrap().join().unwrap();
+    assert!(started < Duration::from_millis(400), "{:?}", started);
+}
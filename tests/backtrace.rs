@@ -0,0 +1,29 @@
+//! backtrace checks a backtrace captured inside a task ends at start_thread, where the stack of
+//! the task begins, instead of running into whatever lies above it. Windows unwinds with tables
+//! of its own, which start_thread doesn't have.
+
+#![cfg(not(windows))]
+
+use std::backtrace::Backtrace;
+
+use green_threads::Runtime;
+
+#[test]
+fn backtraces_of_tasks_end_at_start_thread() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.spawn(|| Backtrace::force_capture().to_string());
+    runtime.run();
+    let backtrace = handle.join().unwrap();
+    // the symbol lines of the frames, which look like "  3: path::to::symbol".
+    let symbols: Vec<_> = backtrace
+        .lines()
+        .filter_map(|line| {
+            let (index, symbol) = line.trim_start().split_once(": ")?;
+            index.parse::<usize>().ok().map(|_| symbol)
+        })
+        .collect();
+    assert!(symbols.len() < 64, "{}", backtrace);
+    let last = symbols.last().unwrap();
+    assert!(last.ends_with("start_thread"), "{}", backtrace);
+}
@@ -0,0 +1,58 @@
+//! canary checks a task writing over the canary at the low end of its stack is caught at its
+//! next yield, with a panic naming the task and the end which was hit.
+
+#![cfg(any(debug_assertions, feature = "stack-canaries"))]
+
+use std::panic;
+use std::ptr;
+
+use green_threads::{yield_thread, JoinError, Runtime, TaskBuilder};
+
+const STACK_CANARY: u64 = 0x5afe_57ac_c0de_d00d;
+const STACK_SIZE: usize = 16 * 1024;
+
+// clobber_low_canary finds the canary at the low end of the calling task's stack, going down
+// from a local, and writes over it.
+fn clobber_low_canary() {
+    let local = 0u64;
+    let mut addr = ptr::addr_of!(local) as usize & !7;
+    for _ in 0..STACK_SIZE / 8 {
+        unsafe {
+            if ptr::read_volatile(addr as *const u64) == STACK_CANARY {
+                ptr::write_volatile(addr as *mut u64, 0);
+                return;
+            }
+        }
+        addr -= 8;
+    }
+    panic!("no canary below the stack of the task");
+}
+
+#[test]
+#[should_panic(expected = "stack canary clobbered at the low end of the stack of task")]
+fn clobbering_a_canary_panics_at_the_next_yield() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = TaskBuilder::new()
+        .name("clobberer")
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            clobber_low_canary();
+            yield_thread();
+        })
+        .unwrap();
+    let id = handle.id();
+    runtime.run();
+    match handle.join() {
+        Err(JoinError::Panicked(payload)) => {
+            let message = payload.downcast_ref::<String>().unwrap();
+            assert!(
+                message.ends_with(&format!("{:?} 'clobberer'", id)),
+                "{}",
+                message
+            );
+            panic::resume_unwind(payload);
+        }
+        _ => panic!("the task didn't panic"),
+    }
+}
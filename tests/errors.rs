@@ -0,0 +1,122 @@
+//! errors checks the fallible functions report why they can't do what they're asked, before
+//! a runtime is initialized and while it's shutting down.
+
+use green_threads::{
+    in_runtime, shutdown, spawn_local, try_spawn, try_yield, unpark, Error, JoinError, Runtime,
+    TaskBuilder,
+};
+
+#[test]
+fn calls_before_init_fail_with_not_initialized() {
+    assert_eq!(try_spawn(|| ()).err(), Some(Error::NotInitialized));
+    assert_eq!(spawn_local(|| ()).err(), Some(Error::NotInitialized));
+    assert_eq!(
+        TaskBuilder::new().spawn(|| ()).err(),
+        Some(Error::NotInitialized)
+    );
+    assert_eq!(try_yield(), Err(Error::NotInitialized));
+
+    // a runtime which exists but isn't initialized isn't used either.
+    let mut runtime = Runtime::new();
+    let handle = runtime.spawn(|| ());
+    assert_eq!(try_spawn(|| ()).err(), Some(Error::NotInitialized));
+    assert_eq!(unpark(handle.id()), Err(Error::NotInitialized));
+}
+
+#[test]
+#[should_panic(expected = "failed to yield: runtime is not initialized on this thread")]
+fn yield_thread_before_init_panics() {
+    green_threads::yield_thread();
+}
+
+#[test]
+fn spawns_after_shutdown_fail_with_shutting_down() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.spawn(|| {
+        shutdown();
+        (
+            try_spawn(|| ()).err(),
+            spawn_local(|| ()).err(),
+            TaskBuilder::new().spawn(|| ()).err(),
+        )
+    });
+    runtime.run();
+    let (spawned, local, built) = handle.join().unwrap();
+    assert_eq!(spawned, Some(Error::ShuttingDown));
+    assert_eq!(local, Some(Error::ShuttingDown));
+    assert_eq!(built, Some(Error::ShuttingDown));
+
+    // the runtime takes tasks again once run has returned.
+    let handle = try_spawn(|| 1).unwrap();
+    runtime.run();
+    assert_eq!(handle.join().unwrap(), 1);
+}
+
+#[test]
+fn a_shutdown_before_run_fails_spawns_until_run_returns() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    runtime.shutdown();
+    assert_eq!(try_spawn(|| ()).err(), Some(Error::ShuttingDown));
+    fn ignore(_: u32) {}
+    assert_eq!(runtime.spawn_with_arg(ignore, 1), Err(Error::ShuttingDown));
+    runtime.run();
+    assert!(try_spawn(|| ()).is_ok());
+}
+
+#[test]
+fn dropping_a_runtime_leaves_the_one_initialized_after_it() {
+    let first = Runtime::new();
+    first.init();
+    let second = Runtime::new();
+    second.init();
+    drop(first);
+    assert!(in_runtime());
+    assert!(try_spawn(|| ()).is_ok());
+    drop(second);
+    assert!(!in_runtime());
+}
+
+#[test]
+fn spawn_with_arg_fails_without_a_free_thread() {
+    let mut runtime = Runtime::builder().max_threads(1).build();
+    runtime.init();
+    fn ignore(_: u32) {}
+    assert_eq!(runtime.spawn_with_arg(ignore, 1), Ok(()));
+    assert_eq!(
+        runtime.spawn_with_arg(ignore, 2),
+        Err(Error::NoAvailableThread)
+    );
+    runtime.run();
+
+    let mut runtime = Runtime::builder().max_tasks(1).build();
+    runtime.init();
+    runtime.spawn(|| ());
+    assert_eq!(
+        runtime.spawn_with_arg(ignore, 3),
+        Err(Error::TooManyTasks(1))
+    );
+    runtime.run();
+}
+
+#[test]
+fn handle_spawns_beyond_max_tasks_are_cancelled() {
+    let mut runtime = Runtime::builder().max_tasks(1).build();
+    runtime.init();
+    let handle = runtime.handle();
+    let remote = handle.spawn(|| 2);
+    let local = runtime.spawn(|| 1);
+    drop(handle);
+    // the task spawned here takes the only slot before the runtime gets to queue the remote one.
+    assert_eq!(runtime.run(), 1);
+    assert_eq!(local.join().unwrap(), 1);
+    assert!(matches!(remote.join(), Err(JoinError::Cancelled)));
+
+    // once the slot is free, a task spawned through a Handle runs again.
+    let handle = runtime.handle();
+    let remote = handle.spawn(|| 3);
+    drop(handle);
+    assert_eq!(runtime.run(), 0);
+    assert_eq!(remote.join().unwrap(), 3);
+}
@@ -0,0 +1,80 @@
+//! net checks tasks talk over TCP sockets without blocking the runtime, and that a task waiting
+//! for a socket is parked rather than scheduled again and again.
+
+#![cfg(target_os = "linux")]
+
+use std::io::{Read, Write};
+use std::net;
+
+use green_threads::net::{TcpListener, TcpStream};
+use green_threads::{yield_thread, JoinError, Runtime};
+
+#[test]
+fn tasks_echo_over_loopback() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // echoes everything back until the client closes the connection.
+    runtime.spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 16];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).unwrap();
+        }
+    });
+    let client = runtime.spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut echoed = Vec::new();
+        for message in ["ping", "pong"] {
+            stream.write_all(message.as_bytes()).unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            echoed.extend_from_slice(&buf);
+        }
+        String::from_utf8(echoed).unwrap()
+    });
+    runtime.run();
+    assert_eq!(client.join().unwrap(), "pingpong");
+}
+
+#[test]
+fn a_task_waiting_for_a_socket_isnt_scheduled() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepting = runtime.spawn(move || listener.accept().map(|_| ()).unwrap());
+    runtime.spawn(move || {
+        for _ in 0..1000 {
+            yield_thread();
+        }
+        // the backlog takes the connection right away, so this doesn't block.
+        net::TcpStream::connect(addr).unwrap()
+    });
+    runtime.run();
+    accepting.join().unwrap();
+    // only the yielding task and the base thread take turns while the other one waits, which
+    // would make it three turns per yield if it was polling its socket.
+    let yields = runtime.stats().yields;
+    assert!(yields < 2500, "{} yields", yields);
+}
+
+#[test]
+fn aborting_a_task_waiting_for_a_socket_lets_run_return() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let accepting = runtime.spawn(move || listener.accept().map(|_| ()).unwrap());
+    let handle = runtime.spawn(move || {
+        yield_thread();
+        accepting.abort();
+        accepting.join()
+    });
+    runtime.run();
+    assert!(matches!(handle.join().unwrap(), Err(JoinError::Cancelled)));
+}
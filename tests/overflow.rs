@@ -0,0 +1,49 @@
+//! overflow checks a task overflowing its stack hits the guard page and aborts the process with
+//! a message, rather than overwriting whatever lies below. The test runs itself again in a
+//! child process, which is the one to overflow.
+
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::hint::black_box;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+
+use green_threads::Runtime;
+
+// set in the environment of the child process, which overflows instead of checking.
+const CHILD: &str = "GREEN_THREADS_OVERFLOW_CHILD";
+const SIGABRT: i32 = 6;
+
+// recurse until the stack overflows, with a frame the optimizer can't get rid of.
+fn recurse(depth: usize) -> usize {
+    if depth == black_box(usize::MAX) {
+        return 0;
+    }
+    let frame = black_box([depth as u8; 512]);
+    recurse(depth + 1) + frame[depth % 512] as usize
+}
+
+#[test]
+fn overflowing_a_green_stack_aborts() {
+    if env::var_os(CHILD).is_some() {
+        let mut runtime = Runtime::new();
+        runtime.init();
+        runtime.spawn_with_stack(64 * 1024, || recurse(0)).unwrap();
+        runtime.run();
+        unreachable!("the task returned from infinite recursion");
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "overflowing_a_green_stack_aborts", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.signal(), Some(SIGABRT), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("stack overflow in green thread"),
+        "stderr: {}",
+        stderr
+    );
+}
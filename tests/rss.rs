@@ -0,0 +1,65 @@
+//! rss checks task stacks are only reserved, so thousands of idle tasks with the default 2 MiB
+//! stacks cost the few pages they touch rather than all of their stacks. It reads the resident
+//! set size of the process from /proc, which only Linux has.
+
+#![cfg(target_os = "linux")]
+
+use std::cell::Cell;
+use std::fs;
+use std::rc::Rc;
+
+use green_threads::{spawn_local, yield_thread, Runtime};
+
+const TASKS: usize = 2000;
+
+// the resident set size of the process in KiB.
+fn rss_kib() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .unwrap();
+    line.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn idle_tasks_only_commit_the_pages_they_touch() {
+    let mut runtime = Runtime::builder().max_threads(TASKS + 1).build();
+    runtime.init();
+    let before = rss_kib();
+    let started = Rc::new(Cell::new(0));
+    let done = Rc::new(Cell::new(false));
+    for _ in 0..TASKS {
+        let (started, done) = (started.clone(), done.clone());
+        spawn_local(move || {
+            started.set(started.get() + 1);
+            while !done.get() {
+                yield_thread();
+            }
+        })
+        .unwrap();
+    }
+    let measured = Rc::new(Cell::new(0));
+    {
+        let (started, done, measured) = (started.clone(), done.clone(), measured.clone());
+        spawn_local(move || {
+            while started.get() < TASKS {
+                yield_thread();
+            }
+            measured.set(rss_kib());
+            done.set(true);
+        })
+        .unwrap();
+    }
+    runtime.run();
+    assert_eq!(started.get(), TASKS);
+    // committing every stack would take 4000 MiB, while touching a few pages of each takes 10 to
+    // 25 MiB.
+    let grown = measured.get().saturating_sub(before);
+    assert!(
+        grown < 256 * 1024,
+        "{} idle tasks grew the RSS by {} KiB",
+        TASKS,
+        grown
+    );
+}
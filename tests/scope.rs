@@ -0,0 +1,52 @@
+//! scope checks Runtime::scope waits for its threads without spinning while they wait. It
+//! measures the CPU time of the OS thread, which only Linux is asked for here.
+
+#![cfg(target_os = "linux")]
+
+use std::thread;
+use std::time::Duration;
+
+use green_threads::{spawn_blocking, Runtime};
+
+// the CPU time the calling OS thread has used so far.
+fn thread_cpu_time() -> Duration {
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: i64,
+        tv_nsec: i64,
+    }
+    extern "C" {
+        fn clock_gettime(clock: i32, ts: *mut Timespec) -> i32;
+    }
+    const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+    let mut ts = Timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    assert_eq!(
+        unsafe { clock_gettime(CLOCK_THREAD_CPUTIME_ID, &mut ts) },
+        0
+    );
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[test]
+fn a_scope_waiting_for_a_blocking_call_doesnt_spin() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let mut result = 0;
+    let start = thread_cpu_time();
+    runtime.scope(|s| {
+        s.spawn(|| {
+            result = spawn_blocking(|| {
+                thread::sleep(Duration::from_millis(300));
+                1
+            })
+            .join()
+            .unwrap();
+        });
+    });
+    let used = thread_cpu_time() - start;
+    assert_eq!(result, 1);
+    assert!(used < Duration::from_millis(100), "{:?}", used);
+}
@@ -0,0 +1,143 @@
+//! shutdown checks a shutdown drops the tasks which haven't started, unwinds the others and
+//! lets whoever waits for them go on.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use green_threads::{is_shutting_down, park, shutdown, sleep, yield_thread, JoinError, Runtime};
+
+#[test]
+fn a_shutdown_inside_a_scope_lets_it_return() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let (started, finished) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    runtime.scope(|s| {
+        for i in 0..8 {
+            let (started, finished) = (&started, &finished);
+            s.spawn(move || {
+                started.fetch_add(1, Ordering::Relaxed);
+                if i == 0 {
+                    shutdown();
+                }
+                yield_thread();
+                finished.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+    });
+    // the tasks which had started are aborted at their yield, the others never start.
+    assert!(started.load(Ordering::Relaxed) < 8);
+    assert_eq!(finished.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn a_shutdown_drops_the_tasks_which_havent_started() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let ran = Arc::new(AtomicUsize::new(0));
+    for _ in 0..1000 {
+        let ran = ran.clone();
+        runtime.spawn(move || {
+            if ran.fetch_add(1, Ordering::Relaxed) + 1 == 10 {
+                shutdown();
+            }
+            for _ in 0..3 {
+                yield_thread();
+            }
+        });
+    }
+    let start = Instant::now();
+    let abandoned = runtime.run();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let ran = ran.load(Ordering::Relaxed);
+    assert!(ran < 20, "{} tasks ran", ran);
+    assert_eq!(ran as u64 + abandoned, 1000);
+    assert_eq!(runtime.stats().abandoned, abandoned);
+}
+
+#[test]
+fn started_tasks_run_once_more_before_they_are_aborted() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let noticed = Arc::new(AtomicBool::new(false));
+    let worker_noticed = noticed.clone();
+    let worker = runtime.spawn(move || loop {
+        if is_shutting_down() {
+            worker_noticed.store(true, Ordering::Relaxed);
+        }
+        yield_thread();
+    });
+    runtime.spawn(|| {
+        yield_thread();
+        shutdown();
+        yield_thread();
+    });
+    assert_eq!(runtime.run(), 0);
+    assert!(noticed.load(Ordering::Relaxed));
+    assert!(matches!(worker.join(), Err(JoinError::Cancelled)));
+}
+
+#[test]
+fn a_handle_shuts_the_runtime_down_from_another_os_thread() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.handle();
+    // parks until the shutdown wakes it up to unwind.
+    let parked = runtime.spawn(|| loop {
+        park();
+    });
+    let remote = handle.clone();
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        remote.shutdown();
+    });
+    runtime.run();
+    stopper.join().unwrap();
+    assert!(matches!(parked.join(), Err(JoinError::Cancelled)));
+    drop(handle);
+}
+
+#[test]
+fn a_shutdown_frees_what_queued_tasks_capture() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let captured = Arc::new(());
+    let handles: Vec<_> = (0..10)
+        .map(|_| {
+            let captured = captured.clone();
+            runtime.spawn(move || drop(captured))
+        })
+        .collect();
+    runtime.shutdown();
+    assert_eq!(runtime.run(), 10);
+    assert_eq!(Arc::strong_count(&captured), 1);
+    for handle in handles {
+        assert!(matches!(handle.join(), Err(JoinError::Cancelled)));
+    }
+}
+
+#[test]
+fn a_handle_wakes_up_a_runtime_waiting_for_a_sleeper() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.handle();
+    let sleeper = runtime.spawn(|| loop {
+        sleep(Duration::from_secs(2));
+    });
+    let remote = handle.clone();
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        remote.shutdown();
+    });
+    let start = Instant::now();
+    runtime.run();
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    stopper.join().unwrap();
+    assert!(matches!(sleeper.join(), Err(JoinError::Cancelled)));
+    drop(handle);
+}
@@ -0,0 +1,215 @@
+//! spawn checks what spawned closures capture gets where it's needed, and is dropped exactly
+//! once however the task ends.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use green_threads::{sleep, spawn, spawn_blocking, spawn_local, yield_thread, JoinError, Runtime};
+
+// Dropped counts how many times it's been dropped.
+struct Dropped(Arc<AtomicUsize>);
+
+impl Drop for Dropped {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn closures_capture_a_vec_and_an_arc() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..5)
+        .map(|i| {
+            let numbers: Vec<usize> = (0..=i).collect();
+            let counter = counter.clone();
+            runtime.spawn(move || {
+                yield_thread();
+                counter.fetch_add(1, Ordering::Relaxed);
+                numbers.iter().sum::<usize>()
+            })
+        })
+        .collect();
+    // every closure holds a clone until it has run and been dropped.
+    assert_eq!(Arc::strong_count(&counter), 6);
+    runtime.run();
+    let sums: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(sums, [0, 1, 3, 6, 10]);
+    assert_eq!(counter.load(Ordering::Relaxed), 5);
+    assert_eq!(Arc::strong_count(&counter), 1);
+}
+
+#[test]
+fn closures_are_dropped_exactly_once() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let drops = Arc::new(AtomicUsize::new(0));
+    // one returns, one panics, and one is aborted before it starts.
+    let dropped = Dropped(drops.clone());
+    let finished = runtime.spawn(move || drop(dropped));
+    let dropped = Dropped(drops.clone());
+    let panicked = runtime.spawn(move || {
+        let _dropped = dropped;
+        panic!("boom");
+    });
+    let dropped = Dropped(drops.clone());
+    let aborted = runtime.spawn(move || drop(dropped));
+    aborted.abort();
+    runtime.run();
+    assert!(finished.join().is_ok());
+    assert!(matches!(panicked.join(), Err(JoinError::Panicked(_))));
+    assert!(matches!(aborted.join(), Err(JoinError::Cancelled)));
+    assert_eq!(drops.load(Ordering::Relaxed), 3);
+    assert_eq!(Arc::strong_count(&drops), 1);
+}
+
+#[test]
+fn nested_joins_outgrow_the_threads() {
+    // every task joins a child which can only run once a thread is free for it, so with
+    // two threads and eight levels of nesting the runtime has to add threads.
+    fn nest(depth: usize) -> usize {
+        if depth == 0 {
+            return 0;
+        }
+        spawn(move || nest(depth - 1)).join().unwrap() + 1
+    }
+    let mut runtime = Runtime::builder().max_threads(2).build();
+    runtime.init();
+    let handles: Vec<_> = (0..3).map(|_| runtime.spawn(|| nest(8))).collect();
+    runtime.run();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 8);
+    }
+}
+
+#[test]
+fn aborting_a_running_task_unwinds_it_at_its_yield() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let drops = Arc::new(AtomicUsize::new(0));
+    let dropped = Dropped(drops.clone());
+    let looping = runtime.spawn(move || {
+        let _dropped = dropped;
+        loop {
+            yield_thread();
+        }
+    });
+    let handle = runtime.spawn(move || {
+        yield_thread();
+        looping.abort();
+        looping.join()
+    });
+    runtime.run();
+    assert!(matches!(handle.join().unwrap(), Err(JoinError::Cancelled)));
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn a_panicking_task_leaves_its_siblings_running() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let before = runtime.spawn(|| {
+        yield_thread();
+        1
+    });
+    let panicking = runtime.spawn(|| -> i32 {
+        yield_thread();
+        panic!("boom")
+    });
+    let after = runtime.spawn(|| {
+        yield_thread();
+        yield_thread();
+        2
+    });
+    runtime.run();
+    assert_eq!(before.join().unwrap() + after.join().unwrap(), 3);
+    match panicking.join() {
+        Err(JoinError::Panicked(payload)) => assert_eq!(payload.downcast_ref(), Some(&"boom")),
+        _ => panic!("the task didn't panic"),
+    }
+}
+
+#[test]
+fn spawn_with_arg_moves_the_argument_to_the_task() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let counter = Arc::new(AtomicUsize::new(0));
+    fn add(counter: Arc<AtomicUsize>) {
+        yield_thread();
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    for _ in 0..3 {
+        runtime.spawn_with_arg(add, counter.clone()).unwrap();
+    }
+    runtime.run();
+    assert_eq!(counter.load(Ordering::Relaxed), 3);
+    assert_eq!(Arc::strong_count(&counter), 1);
+}
+
+#[test]
+fn spawn_local_takes_closures_which_arent_send() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.spawn(|| {
+        let shared = Rc::new(Cell::new(0));
+        let children: Vec<_> = (1..=3)
+            .map(|i| {
+                let shared = shared.clone();
+                spawn_local(move || shared.set(shared.get() + i)).unwrap()
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+        shared.get()
+    });
+    runtime.run();
+    assert_eq!(handle.join().unwrap(), 6);
+}
+
+#[test]
+fn spawn_blocking_runs_on_another_os_thread() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let runtime_thread = thread::current().id();
+    let handle = runtime.spawn(|| {
+        let blocking = spawn_blocking(|| thread::current().id());
+        // the other tasks keep running while the closure blocks.
+        let sibling = spawn(|| 1);
+        (blocking.join().unwrap(), sibling.join().unwrap())
+    });
+    runtime.run();
+    let (blocking_thread, sibling) = handle.join().unwrap();
+    assert_ne!(blocking_thread, runtime_thread);
+    assert_eq!(sibling, 1);
+}
+
+#[test]
+fn aborting_a_sleeping_task_unwinds_it_right_away() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let drops = Arc::new(AtomicUsize::new(0));
+    let dropped = Dropped(drops.clone());
+    let sleeping = runtime.spawn(move || {
+        let _dropped = dropped;
+        sleep(Duration::from_secs(2));
+    });
+    runtime.spawn(move || {
+        yield_thread();
+        sleeping.abort();
+        sleeping.join()
+    });
+    let start = Instant::now();
+    runtime.run();
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+}
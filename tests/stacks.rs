@@ -0,0 +1,43 @@
+//! stacks checks tasks run on the stacks of the tasks before them instead of allocating new
+//! ones, so the number of stacks alive stays put however many tasks run.
+
+use green_threads::{spawn, Runtime};
+
+#[test]
+fn sequential_tasks_leave_the_live_stacks_at_their_baseline() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    for i in 0..200 {
+        let handle = runtime.spawn(move || i);
+        runtime.run();
+        assert_eq!(handle.join().unwrap(), i);
+        assert_eq!(runtime.stats().live_stacks(), 1);
+    }
+    let stats = runtime.stats();
+    assert_eq!(stats.stacks, 1);
+    assert_eq!(stats.stacks_reused, 199);
+}
+
+#[test]
+fn tasks_reuse_pooled_stacks() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    // children joined one after another, next to a task with a stack of another size, which
+    // swaps the stacks of a thread in and out of the pool.
+    let round = |runtime: &mut Runtime| {
+        runtime.spawn(|| {
+            (0..10)
+                .map(|i| spawn(move || i).join().unwrap())
+                .sum::<i32>()
+        });
+        runtime.spawn_with_stack(64 * 1024, || ()).unwrap();
+        runtime.run();
+        runtime.stats().stacks
+    };
+    let allocated = round(&mut runtime);
+    for _ in 0..20 {
+        assert_eq!(round(&mut runtime), allocated);
+    }
+    let stats = runtime.stats();
+    assert_eq!(stats.stacks + stats.stacks_reused, stats.spawned);
+}
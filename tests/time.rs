@@ -0,0 +1,56 @@
+//! time checks sleep and spawn_after wait for at least as long as asked, and let other tasks run
+//! in the meantime.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use green_threads::{sleep, JoinError, Runtime};
+
+#[test]
+fn sleeping_tasks_wake_up_in_the_order_of_their_deadlines() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let start = Instant::now();
+    for ms in [60, 20, 40] {
+        let log = log.clone();
+        runtime.spawn(move || {
+            sleep(Duration::from_millis(ms));
+            log.lock().unwrap().push(ms);
+        });
+    }
+    runtime.run();
+    assert_eq!(*log.lock().unwrap(), [20, 40, 60]);
+    // they slept at the same time, not one after another.
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(60));
+    assert!(elapsed < Duration::from_millis(110), "{:?}", elapsed);
+}
+
+#[test]
+fn spawn_after_starts_the_task_once_the_delay_is_over() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let start = Instant::now();
+    let delayed = runtime.spawn_after(Duration::from_millis(20), move || start.elapsed());
+    let right_away = runtime.spawn(move || start.elapsed());
+    runtime.run();
+    assert!(delayed.join().unwrap() >= Duration::from_millis(20));
+    assert!(right_away.join().unwrap() < Duration::from_millis(20));
+}
+
+#[test]
+fn aborting_a_delayed_task_lets_run_return_right_away() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let delayed = runtime.spawn_after(Duration::from_secs(2), || ());
+    delayed.abort();
+    let start = Instant::now();
+    assert_eq!(runtime.run(), 1);
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    assert!(matches!(delayed.join(), Err(JoinError::Cancelled)));
+}
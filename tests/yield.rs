@@ -0,0 +1,40 @@
+//! yield checks what yield_thread tells its caller about the other tasks.
+
+use green_threads::{yield_thread, yield_thread_front, Runtime};
+
+#[test]
+fn a_single_task_always_gets_false() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handle = runtime.spawn(|| {
+        let mut switched: Vec<_> = (0..5).map(|_| yield_thread()).collect();
+        switched.push(yield_thread_front());
+        switched
+    });
+    runtime.run();
+    assert_eq!(handle.join().unwrap(), [false; 6]);
+}
+
+#[test]
+fn two_tasks_ping_ponging_get_true() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    let handles: Vec<_> = (0..2)
+        .map(|_| runtime.spawn(|| (0..5).map(|_| yield_thread()).collect::<Vec<_>>()))
+        .collect();
+    runtime.run();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), [true; 5]);
+    }
+}
+
+#[test]
+fn a_task_left_alone_gets_false_again() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+    runtime.spawn(yield_thread);
+    let handle = runtime.spawn(|| (0..3).map(|_| yield_thread()).collect::<Vec<_>>());
+    runtime.run();
+    // the other task finishes during the first yield.
+    assert_eq!(handle.join().unwrap(), [true, false, false]);
+}